@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+    unless_empty,
+};
+use error::CorosError;
+use Result;
+
+/// The most recent messages, kept until capacity newer ones have been sent.
+/// Messages are numbered in the order they're sent, each receiver keeps the
+/// number of the next message it will receive.
+struct Broadcast<M: Send> {
+    capacity: usize,
+    messages: VecDeque<M>,
+    next_sequence: u64,
+    parked_receivers: Vec<BlockedMessage>,
+    receiver_count: usize,
+    sender_count: usize,
+}
+
+impl<M: Send> Broadcast<M> {
+    fn oldest_sequence(&self) -> u64 {
+        self.next_sequence - self.messages.len() as u64
+    }
+
+    fn park_receiver(&mut self, blocked_message: BlockedMessage, next_sequence: u64) -> Result<()> {
+        if next_sequence != self.next_sequence || self.sender_count == 0 {
+            return blocked_message.wake()
+        }
+        self.parked_receivers.push(blocked_message);
+
+        Ok(())
+    }
+
+    fn take_parked_receivers(&mut self) -> Vec<BlockedMessage> {
+        mem::replace(&mut self.parked_receivers, Vec::new())
+    }
+}
+
+fn lock_broadcast<M: Send>(broadcast: &Mutex<Broadcast<M>>) -> MutexGuard<Broadcast<M>> {
+    broadcast
+        .lock()
+        .expect("Coros internal error: broadcast lock poisoned")
+}
+
+fn wake_all(parked_receivers: Vec<BlockedMessage>) {
+    for blocked_message in parked_receivers {
+        if let Err(err) = blocked_message.wake() {
+            error!("Error waking coroutine parked on broadcast: {:?}", err);
+        }
+    }
+}
+
+/// Senders can be cloned, the broadcast closes once they've all been dropped
+pub struct BroadcastSender<M: Send> {
+    broadcast: Arc<Mutex<Broadcast<M>>>,
+}
+
+impl<M: Send> BroadcastSender<M> {
+    /// Sends the message to every receiver without blocking, waking all of
+    /// the parked ones. Only the last capacity messages are kept, receivers
+    /// further behind than that miss the oldest. Returns ChannelClosed if
+    /// there are no receivers to send to.
+    pub fn send(&self, message: M) -> Result<()> {
+        let parked_receivers = {
+            let mut broadcast = lock_broadcast(&self.broadcast);
+            if broadcast.receiver_count == 0 {
+                return Err(CorosError::ChannelClosed)
+            }
+            if broadcast.messages.len() == broadcast.capacity {
+                broadcast.messages.pop_front();
+            }
+            broadcast.messages.push_back(message);
+            broadcast.next_sequence += 1;
+
+            broadcast.take_parked_receivers()
+        };
+        wake_all(parked_receivers);
+
+        Ok(())
+    }
+
+    /// A new receiver, which receives the messages sent from now on
+    pub fn subscribe(&self) -> BroadcastReceiver<M> {
+        let next_sequence = {
+            let mut broadcast = lock_broadcast(&self.broadcast);
+            broadcast.receiver_count += 1;
+
+            broadcast.next_sequence
+        };
+
+        BroadcastReceiver {
+            broadcast: self.broadcast.clone(),
+            next_sequence: next_sequence,
+        }
+    }
+}
+
+impl<M: Send> Clone for BroadcastSender<M> {
+    fn clone(&self) -> BroadcastSender<M> {
+        lock_broadcast(&self.broadcast).sender_count += 1;
+
+        BroadcastSender {
+            broadcast: self.broadcast.clone(),
+        }
+    }
+}
+
+/// The last sender to go wakes every parked receiver so they see the
+/// broadcast is closed
+impl<M: Send> Drop for BroadcastSender<M> {
+    fn drop(&mut self) {
+        let parked_receivers = match self.broadcast.lock() {
+            Ok(mut broadcast) => {
+                broadcast.sender_count -= 1;
+                if broadcast.sender_count > 0 {
+                    return
+                }
+
+                broadcast.take_parked_receivers()
+            },
+            Err(_) => return,
+        };
+        wake_all(parked_receivers);
+    }
+}
+
+impl<M: Send> panic::RecoverSafe for BroadcastSender<M> {}
+impl<M: Send> panic::RefRecoverSafe for BroadcastSender<M> {}
+
+/// Received from inside a coroutine with IoHandle::recv_broadcast. A cloned
+/// receiver starts from the same message as the one it was cloned from.
+pub struct BroadcastReceiver<M: Send> {
+    broadcast: Arc<Mutex<Broadcast<M>>>,
+    next_sequence: u64,
+}
+
+impl<M: Clone + Send> BroadcastReceiver<M> {
+    /// Returns the next message without blocking. A receiver that fell so
+    /// far behind that messages were dropped before it received them gets
+    /// Lagged with how many it missed, and then carries on from the oldest
+    /// message still kept. After the last sender is dropped the remaining
+    /// messages are still received before this returns ChannelClosed.
+    pub fn try_recv(&mut self) -> Result<M> {
+        let broadcast = lock_broadcast(&self.broadcast);
+        let oldest_sequence = broadcast.oldest_sequence();
+        if self.next_sequence < oldest_sequence {
+            let missed = oldest_sequence - self.next_sequence;
+            self.next_sequence = oldest_sequence;
+
+            return Err(CorosError::Lagged(missed as usize))
+        }
+        if self.next_sequence == broadcast.next_sequence {
+            if broadcast.sender_count == 0 {
+                return Err(CorosError::ChannelClosed)
+            }
+            return Err(CorosError::ChannelEmpty)
+        }
+
+        let message = broadcast.messages[(self.next_sequence - oldest_sequence) as usize].clone();
+        self.next_sequence += 1;
+
+        Ok(message)
+    }
+}
+
+impl<M: Clone + Send + 'static> Waitable for BroadcastReceiver<M> {
+    type Item = M;
+
+    fn try_take(&mut self) -> Option<Result<M>> {
+        unless_empty(self.try_recv())
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let broadcast = self.broadcast.clone();
+        let next_sequence = self.next_sequence;
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_broadcast(&broadcast).park_receiver(blocked_message, next_sequence)
+        })
+    }
+
+    /// Every parked receiver is woken for each message, so there's no wakeup
+    /// to pass on
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        lock_broadcast(&self.broadcast)
+            .parked_receivers
+            .retain(|blocked_message| blocked_message.wait_id != wait_id);
+    }
+}
+
+impl<M: Send> Clone for BroadcastReceiver<M> {
+    fn clone(&self) -> BroadcastReceiver<M> {
+        lock_broadcast(&self.broadcast).receiver_count += 1;
+
+        BroadcastReceiver {
+            broadcast: self.broadcast.clone(),
+            next_sequence: self.next_sequence,
+        }
+    }
+}
+
+impl<M: Send> Drop for BroadcastReceiver<M> {
+    fn drop(&mut self) {
+        if let Ok(mut broadcast) = self.broadcast.lock() {
+            broadcast.receiver_count -= 1;
+        }
+    }
+}
+
+impl<M: Send> panic::RecoverSafe for BroadcastReceiver<M> {}
+impl<M: Send> panic::RefRecoverSafe for BroadcastReceiver<M> {}
+
+/// A channel where every receiver gets every message. Up to capacity
+/// messages are kept for receivers that have fallen behind.
+pub fn broadcast<M: Clone + Send>(capacity: usize) -> (BroadcastSender<M>, BroadcastReceiver<M>) {
+    assert!(capacity > 0, "Broadcast channels need a capacity of at least one");
+
+    let broadcast = Arc::new(Mutex::new(Broadcast {
+        capacity: capacity,
+        messages: VecDeque::with_capacity(capacity),
+        next_sequence: 0,
+        parked_receivers: Vec::new(),
+        receiver_count: 1,
+        sender_count: 1,
+    }));
+    let tx = BroadcastSender {
+        broadcast: broadcast.clone(),
+    };
+    let rx = BroadcastReceiver {
+        broadcast: broadcast,
+        next_sequence: 0,
+    };
+
+    (tx, rx)
+}
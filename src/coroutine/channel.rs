@@ -1,70 +1,509 @@
+use std::collections::VecDeque;
+use std::mem;
 use std::panic;
-use std::sync::mpsc::{
-    channel,
-    Receiver as StdReceiver,
-    RecvError,
-    Sender as StdSender,
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+use std::sync::atomic::{
+    ATOMIC_USIZE_INIT,
+    AtomicUsize,
+    Ordering,
+};
+use std::sync::mpsc::RecvError;
+use std::thread::{
+    self,
+    Thread,
 };
 
 use mio::Token;
 use mio::Sender as MioSender;
 
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+    unless_empty,
+};
 use error::CorosError;
+pub use coroutine::broadcast::{
+    BroadcastReceiver,
+    BroadcastSender,
+    broadcast,
+};
+pub use coroutine::oneshot::{
+    OneshotReceiver,
+    OneshotSender,
+    oneshot,
+};
+pub use coroutine::watch::{
+    WatchReceiver,
+    WatchSender,
+    watch,
+};
 use Result as CorosResult;
+use scheduler::{
+    SchedulerMessage,
+    Wakeup,
+};
 
+#[derive(Clone)]
 pub struct BlockedMessage {
-    pub mio_tx: MioSender<Token>,
+    pub mio_tx: MioSender<SchedulerMessage>,
     pub token: Token,
+    pub wait_id: usize,
+}
+
+impl BlockedMessage {
+    pub fn wakeup(&self) -> Wakeup {
+        Wakeup {
+            token: self.token,
+            wait_id: self.wait_id,
+        }
+    }
+
+    /// Wakes the parked coroutine through its scheduler's notify handler
+    pub fn wake(self) -> CorosResult<()> {
+        try!(self.mio_tx.send(SchedulerMessage::Wakeup(self.wakeup())));
+
+        Ok(())
+    }
+}
+
+/// Identifies a thread blocked in Receiver::recv or Sender::send, so it can
+/// take itself off the channel after a spurious wakeup
+static NEXT_THREAD_WAIT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct ParkedThread {
+    thread: Thread,
+    wait_id: usize,
+}
+
+/// The queued messages and parked coroutines shared by a channel's ends,
+/// along with any threads blocked sending or receiving. Senders only ever
+/// park on bounded channels. The channel is closed once either all of its
+/// senders or all of its receivers are dropped.
+struct Channel<M: Send> {
+    capacity: Option<usize>,
+    messages: VecDeque<M>,
+    parked_receiver_threads: VecDeque<ParkedThread>,
+    parked_receivers: VecDeque<BlockedMessage>,
+    parked_sender_threads: VecDeque<ParkedThread>,
+    parked_senders: VecDeque<BlockedMessage>,
+    receiver_count: usize,
+    sender_count: usize,
+}
+
+impl<M: Send> Channel<M> {
+    fn new(capacity: Option<usize>) -> Channel<M> {
+        Channel {
+            capacity: capacity,
+            messages: VecDeque::new(),
+            parked_receiver_threads: VecDeque::new(),
+            parked_receivers: VecDeque::new(),
+            parked_sender_threads: VecDeque::new(),
+            parked_senders: VecDeque::new(),
+            receiver_count: 1,
+            sender_count: 1,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        match self.capacity {
+            Some(capacity) => self.messages.len() < capacity,
+            None => true,
+        }
+    }
+
+    fn park_receiver(&mut self, blocked_message: BlockedMessage) -> CorosResult<()> {
+        if !self.messages.is_empty() || self.sender_count == 0 {
+            return blocked_message.wake()
+        }
+        self.parked_receivers.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    fn park_sender(&mut self, blocked_message: BlockedMessage) -> CorosResult<()> {
+        if self.has_room() || self.receiver_count == 0 {
+            return blocked_message.wake()
+        }
+        self.parked_senders.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    /// Called once a parked receiver is running again. A receiver that was
+    /// woken for a message but left without taking one passes the wakeup on,
+    /// so the message isn't stranded while other receivers are parked.
+    fn finish_receive_wait(&mut self, wait_id: usize, received: bool) {
+        let was_parked = remove_parked(&mut self.parked_receivers, wait_id);
+        if was_parked || received || self.messages.is_empty() {
+            return
+        }
+
+        self.wake_next_receiver();
+    }
+
+    /// Parked coroutines are woken ahead of blocked threads
+    fn wake_next_receiver(&mut self) {
+        if let Some(blocked_message) = self.parked_receivers.pop_front() {
+            return wake(blocked_message)
+        }
+        if let Some(parked_thread) = self.parked_receiver_threads.pop_front() {
+            parked_thread.thread.unpark();
+        }
+    }
+
+    fn finish_send_wait(&mut self, wait_id: usize, sent: bool) {
+        let was_parked = remove_parked(&mut self.parked_senders, wait_id);
+        if was_parked || sent || !self.has_room() {
+            return
+        }
+
+        self.wake_next_sender();
+    }
+
+    /// Parked coroutines are woken ahead of blocked threads
+    fn wake_next_sender(&mut self) {
+        if let Some(blocked_message) = self.parked_senders.pop_front() {
+            return wake(blocked_message)
+        }
+        if let Some(parked_thread) = self.parked_sender_threads.pop_front() {
+            parked_thread.thread.unpark();
+        }
+    }
+}
+
+fn remove_parked(parked: &mut VecDeque<BlockedMessage>, wait_id: usize) -> bool {
+    let parked_count = parked.len();
+    parked.retain(|blocked_message| blocked_message.wait_id != wait_id);
+
+    parked.len() != parked_count
+}
+
+fn wake(blocked_message: BlockedMessage) {
+    if let Err(err) = blocked_message.wake() {
+        error!("Error waking coroutine parked on channel: {:?}", err);
+    }
+}
+
+fn lock_channel<M: Send>(channel: &Mutex<Channel<M>>) -> MutexGuard<Channel<M>> {
+    channel
+        .lock()
+        .expect("Coros internal error: channel lock poisoned")
 }
 
+/// Senders can be cloned, the channel closes once they've all been dropped
 pub struct Sender<M: Send> {
-    blocked_message_rx: StdReceiver<BlockedMessage>,
-    user_message_tx: StdSender<M>,
+    channel: Arc<Mutex<Channel<M>>>,
+}
+
+impl<M: Send> Clone for Sender<M> {
+    fn clone(&self) -> Sender<M> {
+        lock_channel(&self.channel).sender_count += 1;
+
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+/// The last sender to go wakes every parked receiver and blocked thread so
+/// they see the channel is closed
+impl<M: Send> Drop for Sender<M> {
+    fn drop(&mut self) {
+        let (parked_receivers, parked_threads) = match self.channel.lock() {
+            Ok(mut channel) => {
+                channel.sender_count -= 1;
+                if channel.sender_count > 0 {
+                    return
+                }
+
+                (
+                    mem::replace(&mut channel.parked_receivers, VecDeque::new()),
+                    mem::replace(&mut channel.parked_receiver_threads, VecDeque::new()),
+                )
+            },
+            Err(_) => return,
+        };
+
+        for blocked_message in parked_receivers {
+            wake(blocked_message);
+        }
+        for parked_thread in parked_threads {
+            parked_thread.thread.unpark();
+        }
+    }
 }
 
 impl<M: Send> Sender<M> {
-    // Is there a way to implement this without blocking while allowing messages to send before
-    // recv is called?
+    /// Queues the message, waking a receiver if one is parked waiting for
+    /// it. Sending on a channel with room never blocks, so coroutines and
+    /// threads can both send on unbounded channels. A full bounded channel
+    /// blocks the calling thread until a message is received, which would
+    /// block a coroutine's whole scheduler, coroutines wait for room with
+    /// IoHandle::send instead. Once every receiver has been dropped this
+    /// returns ChannelClosed.
     pub fn send(&self, message: M) -> CorosResult<()> {
-        let blocked_message = try!(self.blocked_message_rx.recv());
-        try!(
-            blocked_message
-             .mio_tx
-             .send(blocked_message.token)
-        );
+        let wait_id = NEXT_THREAD_WAIT_ID.fetch_add(1, Ordering::SeqCst);
+        let mut message = message;
+        loop {
+            message = match try!(self.offer(message)) {
+                None => return Ok(()),
+                Some(message) => message,
+            };
 
-        if let Err(_) = self.user_message_tx.send(message) {
-            return Err(CorosError::CoroutineChannelSendError)
+            {
+                let mut channel = lock_channel(&self.channel);
+                if channel.has_room() || channel.receiver_count == 0 {
+                    continue
+                }
+                channel.parked_sender_threads.push_back(ParkedThread {
+                    thread: thread::current(),
+                    wait_id: wait_id,
+                });
+            }
+            thread::park();
+
+            // Still queued if the wakeup was spurious
+            lock_channel(&self.channel)
+                .parked_sender_threads
+                .retain(|parked_thread| parked_thread.wait_id != wait_id);
         }
+    }
 
-        Ok(())
+    /// Queues the message if the channel has room, returning ChannelFull
+    /// rather than blocking if it doesn't
+    pub fn try_send(&self, message: M) -> CorosResult<()> {
+        match try!(self.offer(message)) {
+            None => Ok(()),
+            Some(_) => Err(CorosError::ChannelFull),
+        }
+    }
+
+    /// Queues the message if the channel has room, handing it back if not
+    pub fn offer(&self, message: M) -> CorosResult<Option<M>> {
+        let (maybe_parked_receiver, maybe_parked_thread) = {
+            let mut channel = lock_channel(&self.channel);
+            if channel.receiver_count == 0 {
+                return Err(CorosError::ChannelClosed)
+            }
+            if !channel.has_room() {
+                return Ok(Some(message))
+            }
+            channel.messages.push_back(message);
+            let maybe_parked_receiver = channel.parked_receivers.pop_front();
+            let maybe_parked_thread = if maybe_parked_receiver.is_none() {
+                channel.parked_receiver_threads.pop_front()
+            } else {
+                None
+            };
+
+            (maybe_parked_receiver, maybe_parked_thread)
+        };
+
+        if let Some(blocked_message) = maybe_parked_receiver {
+            wake(blocked_message);
+        }
+        if let Some(parked_thread) = maybe_parked_thread {
+            parked_thread.thread.unpark();
+        }
+
+        Ok(None)
+    }
+
+    /// Builds the callback that parks a coroutine until the channel has room
+    pub fn park_callback(&self) -> ParkCallback
+        where M: 'static
+    {
+        let channel = self.channel.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> CorosResult<()> {
+            lock_channel(&channel).park_sender(blocked_message)
+        })
+    }
+
+    /// Called by a coroutine parked with park_callback once it's running
+    /// again, with whether it went on to send its message
+    pub fn finish_wait(&self, wait_id: usize, sent: bool) {
+        lock_channel(&self.channel).finish_send_wait(wait_id, sent);
     }
 }
 
 impl<M: Send> panic::RecoverSafe for Sender<M> {}
 impl<M: Send> panic::RefRecoverSafe for Sender<M> {}
 
+/// Received from inside a coroutine with IoHandle::recv, or from a thread
+/// with Receiver::recv. Receivers can be cloned to share the channel between
+/// several coroutines, each message is received by exactly one of them.
 pub struct Receiver<M: Send> {
-    pub blocked_message_tx: StdSender<BlockedMessage>,
-    user_message_rx: StdReceiver<M>,
+    channel: Arc<Mutex<Channel<M>>>,
+}
+
+impl<M: Send> Clone for Receiver<M> {
+    fn clone(&self) -> Receiver<M> {
+        lock_channel(&self.channel).receiver_count += 1;
+
+        Receiver {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+/// The last receiver to go wakes every parked sender and blocked thread so
+/// they see the channel is closed
+impl<M: Send> Drop for Receiver<M> {
+    fn drop(&mut self) {
+        let (parked_senders, parked_threads) = match self.channel.lock() {
+            Ok(mut channel) => {
+                channel.receiver_count -= 1;
+                if channel.receiver_count > 0 {
+                    return
+                }
+
+                (
+                    mem::replace(&mut channel.parked_senders, VecDeque::new()),
+                    mem::replace(&mut channel.parked_sender_threads, VecDeque::new()),
+                )
+            },
+            Err(_) => return,
+        };
+
+        for blocked_message in parked_senders {
+            wake(blocked_message);
+        }
+        for parked_thread in parked_threads {
+            parked_thread.thread.unpark();
+        }
+    }
 }
 
 impl<M: Send> Receiver<M> {
+    /// Returns a message that has already arrived without blocking, making
+    /// room for a sender parked or blocked on a full bounded channel.
+    /// Messages sent before the last sender was dropped are still received,
+    /// after that this returns ChannelClosed rather than ChannelEmpty.
+    pub fn try_recv(&self) -> CorosResult<M> {
+        let (message, maybe_parked_sender, maybe_parked_thread) = {
+            let mut channel = lock_channel(&self.channel);
+            let maybe_message = channel.messages.pop_front();
+            let message = match maybe_message {
+                Some(message) => message,
+                None if channel.sender_count == 0 => return Err(CorosError::ChannelClosed),
+                None => return Err(CorosError::ChannelEmpty),
+            };
+
+            let maybe_parked_sender = channel.parked_senders.pop_front();
+            let maybe_parked_thread = if maybe_parked_sender.is_none() {
+                channel.parked_sender_threads.pop_front()
+            } else {
+                None
+            };
+
+            (message, maybe_parked_sender, maybe_parked_thread)
+        };
+
+        if let Some(blocked_message) = maybe_parked_sender {
+            wake(blocked_message);
+        }
+        if let Some(parked_thread) = maybe_parked_thread {
+            parked_thread.thread.unpark();
+        }
+
+        Ok(message)
+    }
+
+    /// Blocks the calling thread until a message arrives, or returns
+    /// RecvError once every sender has been dropped and the messages they
+    /// sent have been received. This is for plain threads, a coroutine
+    /// calling it would block its whole scheduler, coroutines receive with
+    /// IoHandle::recv instead.
     pub fn recv(&self) -> Result<M, RecvError> {
-        self.user_message_rx.recv()
+        let wait_id = NEXT_THREAD_WAIT_ID.fetch_add(1, Ordering::SeqCst);
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(CorosError::ChannelEmpty) => {},
+                Err(_) => return Err(RecvError),
+            }
+
+            {
+                let mut channel = lock_channel(&self.channel);
+                if !channel.messages.is_empty() || channel.sender_count == 0 {
+                    continue
+                }
+                channel.parked_receiver_threads.push_back(ParkedThread {
+                    thread: thread::current(),
+                    wait_id: wait_id,
+                });
+            }
+            thread::park();
+
+            // Still queued if the wakeup was spurious
+            lock_channel(&self.channel)
+                .parked_receiver_threads
+                .retain(|parked_thread| parked_thread.wait_id != wait_id);
+        }
+    }
+
+    /// Builds the callback that parks a coroutine until a message arrives
+    pub fn park_callback(&self) -> ParkCallback
+        where M: 'static
+    {
+        let channel = self.channel.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> CorosResult<()> {
+            lock_channel(&channel).park_receiver(blocked_message)
+        })
+    }
+
+    /// Called by a coroutine parked with park_callback once it's running
+    /// again, with whether it went on to receive a message
+    pub fn finish_wait(&self, wait_id: usize, received: bool) {
+        lock_channel(&self.channel).finish_receive_wait(wait_id, received);
+    }
+}
+
+impl<'a, M: Send + 'static> Waitable for &'a Receiver<M> {
+    type Item = M;
+
+    fn try_take(&mut self) -> Option<CorosResult<M>> {
+        unless_empty(self.try_recv())
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        Receiver::park_callback(self)
+    }
+
+    fn finish_wait(&self, wait_id: usize, received: bool) {
+        Receiver::finish_wait(self, wait_id, received);
     }
 }
 
 pub fn new<M: Send>() -> (Sender<M>, Receiver<M>) {
-    let (blocked_message_tx, blocked_message_rx) = channel::<BlockedMessage>();
-    let (user_message_tx, user_message_rx) = channel::<M>();
+    with_capacity(None)
+}
+
+/// A channel that holds at most capacity messages. Coroutines sending on a
+/// full channel with IoHandle::send are parked until a message is received.
+/// A channel with no capacity couldn't hold a message for a receiver to
+/// take, so a capacity of zero returns ZeroCapacityChannel.
+pub fn bounded<M: Send>(capacity: usize) -> CorosResult<(Sender<M>, Receiver<M>)> {
+    if capacity == 0 {
+        return Err(CorosError::ZeroCapacityChannel)
+    }
+
+    Ok(with_capacity(Some(capacity)))
+}
+
+fn with_capacity<M: Send>(capacity: Option<usize>) -> (Sender<M>, Receiver<M>) {
+    let channel = Arc::new(Mutex::new(Channel::new(capacity)));
     let tx = Sender::<M> {
-      blocked_message_rx: blocked_message_rx,
-      user_message_tx: user_message_tx,
+      channel: channel.clone(),
     };
     let rx = Receiver::<M> {
-      blocked_message_tx: blocked_message_tx,
-      user_message_rx: user_message_rx,
+      channel: channel,
     };
 
     (tx, rx)
@@ -1,6 +1,12 @@
-use std::panic::{RecoverSafe, RefRecoverSafe};
-use std::sync::mpsc::channel;
-use std::sync::MutexGuard;
+use std::boxed::FnBox;
+use std::cmp;
+use std::mem;
+use std::panic::{self, RecoverSafe, RefRecoverSafe};
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
 use std::time::Duration;
 
 use mio::{
@@ -8,9 +14,11 @@ use mio::{
     EventSet,
     Evented,
     PollOpt,
+    Token,
 };
 
 use context::Context;
+use time;
 
 use coroutine::{
     EventLoopRegistrationCallback,
@@ -20,160 +28,806 @@ use coroutine::{
 use coroutine::channel::{
     BlockedMessage,
     Receiver,
+    Sender,
+};
+use coroutine::broadcast::BroadcastReceiver;
+use coroutine::join_handle::{
+    CoroutineCancellation,
+    resume_panic,
+};
+use coroutine::oneshot::OneshotReceiver;
+use coroutine::readiness::ReadinessQueue;
+use coroutine::registration::Registration;
+use coroutine::select::{
+    Selected,
+    WaitSpec,
 };
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use coroutine::watch::WatchReceiver;
 use error::CorosError;
+use JoinHandle;
 use Result;
 use scheduler::{
     BlockedCoroutineSlab,
+    IoOwner,
+    IoRegistrations,
     Scheduler,
+    block_coroutine,
+    deregister_io,
+    next_io_token,
+    register_io,
+    reregister_io,
 };
 
+type IoOperation<'a> = Box<FnBox(&mut EventLoop<Scheduler>, &IoRegistrations) -> Result<()> + 'a>;
+
+/// An operation on another scheduler's event loop that borrows from the
+/// coroutine's stack. The coroutine stays suspended until it's run or
+/// dropped, so the borrows outlive it.
+struct BorrowingOperation(IoOperation<'static>);
+
+unsafe impl Send for BorrowingOperation {}
+
+/// Where a BorrowingOperation leaves its result, and the coroutine waiting
+/// for it
+struct OperationResult {
+    maybe_blocked_message: Option<BlockedMessage>,
+    maybe_result: Option<Result<()>>,
+}
+
+/// Sent along with a BorrowingOperation to leave its result and wake the
+/// waiting coroutine. If the operation is dropped without running, because
+/// the scheduler it was sent to shut down first, the coroutine is woken with
+/// IoOwnerShutDown instead.
+struct OperationCompletion {
+    maybe_operation_result: Option<Arc<Mutex<OperationResult>>>,
+}
+
+impl OperationCompletion {
+    fn complete(&mut self, result: Result<()>) {
+        let operation_result = match self.maybe_operation_result.take() {
+            Some(operation_result) => operation_result,
+            None => return,
+        };
+        let maybe_blocked_message = {
+            let mut operation_result = lock_operation_result(&operation_result);
+            operation_result.maybe_result = Some(result);
+            operation_result.maybe_blocked_message.take()
+        };
+
+        if let Some(blocked_message) = maybe_blocked_message {
+            if let Err(err) = blocked_message.wake() {
+                error!("Error waking coroutine after event loop operation: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Drop for OperationCompletion {
+    fn drop(&mut self) {
+        self.complete(Err(CorosError::IoOwnerShutDown));
+    }
+}
+
 pub struct IoHandle<'a> {
     pub coroutine: &'a mut Coroutine,
-    pub scheduler_context: &'a Context,
 }
 
 impl<'a> IoHandle<'a> {
+    /// Spawns a child coroutine onto the work queue of the scheduler running
+    /// this coroutine, where it can be stolen by the pool's other schedulers
+    pub fn spawn<F, T>(&mut self, coroutine_body: F, stack_size: usize) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        let (coroutine, join_handle) = Coroutine::with_join_handle(
+            coroutine_body,
+            stack_size,
+        );
+        self.coroutine.scheduler().push_work(coroutine);
+
+        Ok(join_handle)
+    }
+
+    pub fn spawn_with_default_stack<F, T>(&mut self, coroutine_body: F) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        let stack_size = self.coroutine.scheduler().default_stack_size();
+        self.spawn(coroutine_body, stack_size)
+    }
+
+    /// Sleeping past the coroutine's deadline returns TimedOut
     pub fn sleep(&mut self, duration: Duration) -> Result<()> {
-        self.coroutine.state = CoroutineState::Blocked;
+        match self.park_with_timeout(Some(duration), |_| Ok(())) {
+            Err(CorosError::TimedOut) => {
+                if self.deadline_has_passed() {
+                    Err(CorosError::TimedOut)
+                } else {
+                    Ok(())
+                }
+            },
+            result => result,
+        }
+    }
 
-        let mio_callback = move |coroutine: Coroutine,
-                                 mio_event_loop: &mut EventLoop<Scheduler>,
-                                 blocked_coroutines: &mut BlockedCoroutineSlab| -> Result<()> {
-            let token = match blocked_coroutines.insert((coroutine, None)) {
-                Ok(token) => token,
-                Err(_) => return Err(CorosError::SlabFull),
-            };
+    /// Suspends the coroutine until a message arrives on the channel, or
+    /// returns ChannelClosed once every sender has been dropped
+    pub fn recv<M: Send + 'static>(&mut self, rx: &Receiver<M>) -> Result<M> {
+        self.wait_for(&mut &*rx)
+    }
 
-            try!(mio_event_loop.timeout(token, duration));
+    pub fn recv_timeout<M: Send + 'static>(&mut self, rx: &Receiver<M>, timeout: Duration) -> Result<M> {
+        self.wait_for_with_timeout(&mut &*rx, Some(timeout))
+    }
 
-            Ok(())
+    /// Suspends the coroutine until the oneshot's value is sent, or returns
+    /// ChannelClosed if its sender is dropped without sending one
+    pub fn recv_oneshot<T: Send + 'static>(&mut self, rx: OneshotReceiver<T>) -> Result<T> {
+        let mut rx = rx;
+        self.wait_for(&mut rx)
+    }
+
+    /// Suspends the coroutine until the next broadcast message arrives.
+    /// Returns Lagged if messages were dropped before this receiver got to
+    /// them, receiving again carries on from the oldest message still kept.
+    pub fn recv_broadcast<M>(&mut self, rx: &mut BroadcastReceiver<M>) -> Result<M>
+        where M: Clone + Send + 'static
+    {
+        self.wait_for(rx)
+    }
+
+    /// Suspends the coroutine until the watched value changes from the one
+    /// the receiver last saw, returning the latest value. Returns
+    /// ChannelClosed once the sender has been dropped.
+    pub fn changed<T>(&mut self, rx: &mut WatchReceiver<T>) -> Result<T>
+        where T: Clone + Send + 'static
+    {
+        self.wait_for(rx)
+    }
+
+    /// Takes from the waitable, suspending the coroutine for as long as
+    /// there's nothing to take yet
+    pub fn wait_for<W: Waitable>(&mut self, waitable: &mut W) -> Result<W::Item> {
+        self.wait_for_with_timeout(waitable, None)
+    }
+
+    /// Sends the message, parking the coroutine while a bounded channel is
+    /// full until a receiver makes room. Returns ChannelClosed once every
+    /// receiver has been dropped.
+    pub fn send<M: Send + 'static>(&mut self, tx: &Sender<M>, message: M) -> Result<()> {
+        self.unwind_if_cancelled();
+        let mut message = match try!(tx.offer(message)) {
+            None => return Ok(()),
+            Some(message) => message,
         };
 
-        self.suspend_with_callback(Box::new(mio_callback))
+        loop {
+            let park_callback = tx.park_callback();
+
+            let park_result = self.park_until_woken(None, move |blocked_message: BlockedMessage| -> Result<()> {
+                park_callback.call_box((blocked_message,))
+            });
+
+            let wait_id = self.coroutine.wait_id;
+            match tx.offer(message) {
+                Ok(None) => {
+                    tx.finish_wait(wait_id, true);
+                    return Ok(())
+                },
+                Ok(Some(returned_message)) => {
+                    tx.finish_wait(wait_id, false);
+                    message = returned_message;
+                },
+                Err(err) => {
+                    tx.finish_wait(wait_id, false);
+                    return Err(err)
+                },
+            }
+            self.unwind_if_cancelled();
+            try!(park_result);
+        }
     }
 
-    pub fn recv<M: Send>(&mut self, rx: &MutexGuard<Receiver<M>>) -> Result<M> {
-        let blocked_message_tx = rx.blocked_message_tx.clone();
-        self.coroutine.state = CoroutineState::Blocked;
+    /// Waits for another coroutine to finish without blocking the thread
+    /// running this coroutine
+    pub fn join<T>(&mut self, join_handle: &mut JoinHandle<T>) -> Result<Result<T>>
+        where T: Send + 'static
+    {
+        if join_handle.is_joined {
+            return Err(CorosError::CoroutineAlreadyJoined)
+        }
+        let join_waiter = join_handle.join_waiter.clone();
+
+        let park_result = self.park_until_woken(None, move |blocked_message: BlockedMessage| -> Result<()> {
+            join_waiter
+                .lock()
+                .expect("Coros internal error: join waiter lock poisoned")
+                .wait(blocked_message)
+        });
+        join_handle.join_waiter
+            .lock()
+            .expect("Coros internal error: join waiter lock poisoned")
+            .cancel_wait(self.coroutine.wait_id);
+        self.unwind_if_cancelled();
+        try!(park_result);
+
+        join_handle.join()
+    }
 
-        let mio_callback = move |coroutine: Coroutine,
-                                 mio_event_loop: &mut EventLoop<Scheduler>,
-                                 blocked_coroutines: &mut BlockedCoroutineSlab| -> Result<()> {
-            let token = match blocked_coroutines.insert((coroutine, None)) {
-                Ok(token) => token,
-                Err(_) => return Err(CorosError::SlabFull),
-            };
-            let mio_tx = mio_event_loop.channel();
-            let message = BlockedMessage {
-                mio_tx: mio_tx,
-                token: token,
-            };
+    /// Like join, but continues the other coroutine's panic in this one if
+    /// its body panicked
+    pub fn join_resuming_panic<T>(&mut self, join_handle: &mut JoinHandle<T>) -> Result<T>
+        where T: Send + 'static
+    {
+        resume_panic(try!(self.join(join_handle)))
+    }
+
+    pub fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt) -> Result<EventSet>
+        where E: Evented + 'static
+    {
+        self.register_with_timeout(io, interest, opt, None)
+    }
+
+    /// Registers the IO and waits for its first event, returning TimedOut if
+    /// no event arrives in time. The IO is left registered either way, it's
+    /// up to the caller to deregister it.
+    pub fn register_timeout<E: ?Sized>(
+        &mut self,
+        io: &E,
+        interest: EventSet,
+        opt: PollOpt,
+        timeout: Duration,
+    ) -> Result<EventSet>
+        where E: Evented + 'static
+    {
+        self.register_with_timeout(io, interest, opt, Some(timeout))
+    }
+
+    /// Deregisters the IO from the event loop it was registered with,
+    /// dropping any readiness that built up for it
+    pub fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<()>
+        where E: Evented + 'static
+    {
+        let io_owner = self.home_io_owner();
+        let maybe_io_token = self.coroutine.io_tokens.remove(&io_address(io));
+        if let Some(io_token) = maybe_io_token {
+            lock_readiness(&self.coroutine.readiness).remove(io_token);
+        }
+
+        self.run_on_io_owner(&io_owner, move |mio_event_loop, io_registrations| {
+            deregister_io(mio_event_loop, io_registrations, io, maybe_io_token)
+        })
+    }
+
+    /// Registers the source with the event loop without waiting on it. The
+    /// returned Registration is waited on as many times as needed, and
+    /// deregisters the source when it's dropped.
+    pub fn register_source<E>(&mut self, source: E) -> Result<Registration<E>>
+        where E: Evented + Send + 'static
+    {
+        self.unwind_if_cancelled();
+        let io_token = next_io_token();
+        let scheduler = self.coroutine.scheduler_mut();
+        try!(scheduler.register(
+            &source,
+            io_token,
+            EventSet::all(),
+            PollOpt::edge(),
+            &self.coroutine.readiness,
+        ));
+
+        Ok(Registration::new(
+            source,
+            io_token,
+            scheduler.io_owner(),
+            self.coroutine.readiness.clone(),
+        ))
+    }
+
+    /// Suspends the coroutine until the registered source is ready for any
+    /// of the interest. Readiness that arrived since the source was last
+    /// waited on is returned straight away.
+    pub fn wait<E>(&mut self, registration: &Registration<E>, interest: EventSet) -> Result<EventSet>
+        where E: Evented + Send + 'static
+    {
+        self.unwind_if_cancelled();
+        let maybe_timeout = try!(self.timeout_before_deadline(None));
+
+        match self.park_until_ready(registration.readiness(), registration.io_token(), interest, maybe_timeout) {
+            Some(eventset) => Ok(eventset),
+            None => {
+                self.unwind_if_cancelled();
+
+                Err(CorosError::TimedOut)
+            },
+        }
+    }
 
-            if let Err(_) = blocked_message_tx.send(message) {
-                return Err(CorosError::CoroutineBlockSendError)
+    /// Suspends the coroutine until one of the wait specs fires, returning
+    /// which one. Specs that are already ready are taken in slice order
+    /// without suspending. Timer specs fire once their timeout passes, but
+    /// the coroutine's deadline still applies and returns TimedOut.
+    pub fn select<M: Send + 'static>(&mut self, wait_specs: &[WaitSpec<M>]) -> Result<Selected<M>> {
+        self.unwind_if_cancelled();
+        let maybe_timer = wait_specs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, wait_spec)| match *wait_spec {
+                WaitSpec::Timer(timeout) => Some((time::precise_time_ns() + duration_as_nanos(timeout), index)),
+                _ => None,
+            })
+            .min();
+
+        if let Some(selected) = poll_wait_specs(wait_specs) {
+            return Ok(selected)
+        }
+
+        loop {
+            let maybe_timeout = match maybe_timer {
+                Some((timer_end, index)) => {
+                    let now = time::precise_time_ns();
+                    if now >= timer_end {
+                        return Ok(Selected::Timer(index))
+                    }
+                    Some(nanos_as_duration(timer_end - now))
+                },
+                None => None,
+            };
+            let park_callbacks: Vec<ParkCallback> = wait_specs
+                .iter()
+                .filter_map(|wait_spec| wait_spec.park_callback())
+                .collect();
+
+            let park_result = self.park_until_woken(maybe_timeout, move |blocked_message: BlockedMessage| -> Result<()> {
+                for park_callback in park_callbacks {
+                    try!(park_callback.call_box((blocked_message.clone(),)));
+                }
+
+                Ok(())
+            });
+
+            let wait_id = self.coroutine.wait_id;
+            let maybe_selected = poll_wait_specs(wait_specs);
+            let maybe_selected_index = maybe_selected.as_ref().map(|selected| selected.index());
+            for (index, wait_spec) in wait_specs.iter().enumerate() {
+                wait_spec.finish_wait(wait_id, maybe_selected_index == Some(index));
+            }
+            if let Some(selected) = maybe_selected {
+                return Ok(selected)
+            }
+            self.unwind_if_cancelled();
+
+            match park_result {
+                Err(CorosError::TimedOut) => {
+                    if self.deadline_has_passed() {
+                        return Err(CorosError::TimedOut)
+                    }
+                },
+                Err(err) => return Err(err),
+                // Woken for something another coroutine took first
+                Ok(()) => {},
             }
+        }
+    }
+
+    /// Updates the interest of IO registered by this coroutine and waits for
+    /// its next event. Readiness that arrived since the coroutine last waited
+    /// on the IO is returned straight away. IO is recognised by its address,
+    /// IO that's been moved since it was registered is given a new token,
+    /// which loses the readiness it had built up but not later events.
+    pub fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt) -> Result<EventSet>
+        where E: Evented + 'static
+    {
+        self.unwind_if_cancelled();
+        let maybe_timeout = try!(self.timeout_before_deadline(None));
+        let io_token = match self.coroutine.io_tokens.get(&io_address(io)) {
+            Some(&io_token) => io_token,
+            None => next_io_token(),
+        };
+        let io_owner = self.home_io_owner();
+        let readiness = self.coroutine.readiness.clone();
+        try!(self.run_on_io_owner(&io_owner, move |mio_event_loop, io_registrations| {
+            reregister_io(
+                mio_event_loop,
+                io_registrations,
+                io,
+                io_token,
+                interest,
+                opt,
+                &readiness,
+            )
+        }));
+        self.coroutine.io_tokens.insert(io_address(io), io_token);
+
+        self.wait_for_readiness(io_token, maybe_timeout)
+    }
 
-            Ok(())
+    /// Runs the body with a deadline, any blocking operation in the body that
+    /// would wait past it returns TimedOut instead. Deadlines nest, an inner
+    /// deadline can't extend an outer one.
+    pub fn with_deadline<F, T>(&mut self, timeout: Duration, body: F) -> T
+        where F: FnOnce(&mut IoHandle<'a>) -> T
+    {
+        let deadline = time::precise_time_ns() + duration_as_nanos(timeout);
+        let previous_deadline = self.coroutine.deadline;
+        self.coroutine.deadline = match previous_deadline {
+            Some(previous_deadline) => Some(cmp::min(previous_deadline, deadline)),
+            None => Some(deadline),
         };
 
-        try!(self.suspend_with_callback(Box::new(mio_callback)));
+        let result = body(self);
+        self.coroutine.deadline = previous_deadline;
 
-        Ok(try!(rx.recv()))
+        result
     }
 
-    pub fn register<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt) -> Result<EventSet>
+    pub fn yield_now(&mut self) -> Result<()> {
+        self.unwind_if_cancelled();
+        self.coroutine.state = CoroutineState::Yielded;
+
+        Context::swap(self.coroutine.context(), self.coroutine.scheduler_context());
+        self.unwind_if_cancelled();
+
+        Ok(())
+    }
+
+    /// Whether the coroutine's JoinHandle has aborted it. Long running
+    /// coroutines that don't block can check this to stop early.
+    pub fn is_cancelled(&self) -> bool {
+        self.coroutine
+            .cancellation
+            .lock()
+            .expect("Coros internal error: cancellation lock poisoned")
+            .is_cancelled
+    }
+
+    fn wait_for_with_timeout<W: Waitable>(
+        &mut self,
+        waitable: &mut W,
+        maybe_timeout: Option<Duration>,
+    ) -> Result<W::Item> {
+        self.unwind_if_cancelled();
+        let maybe_timeout_end = maybe_timeout.map(|timeout| {
+            time::precise_time_ns() + duration_as_nanos(timeout)
+        });
+        if let Some(result) = waitable.try_take() {
+            return result
+        }
+
+        loop {
+            let maybe_timeout = match maybe_timeout_end {
+                Some(timeout_end) => {
+                    let now = time::precise_time_ns();
+                    if now >= timeout_end {
+                        return Err(CorosError::TimedOut)
+                    }
+                    Some(nanos_as_duration(timeout_end - now))
+                },
+                None => None,
+            };
+            let park_callback = waitable.park_callback();
+
+            let park_result = self.park_until_woken(maybe_timeout, move |blocked_message: BlockedMessage| -> Result<()> {
+                park_callback.call_box((blocked_message,))
+            });
+
+            let wait_id = self.coroutine.wait_id;
+            let maybe_result = waitable.try_take();
+            let took = match maybe_result {
+                Some(Ok(_)) => true,
+                _ => false,
+            };
+            waitable.finish_wait(wait_id, took);
+            if let Some(result) = maybe_result {
+                return result
+            }
+            self.unwind_if_cancelled();
+            try!(park_result);
+        }
+    }
+
+    fn register_with_timeout<E: ?Sized>(
+        &mut self,
+        io: &E,
+        interest: EventSet,
+        opt: PollOpt,
+        maybe_timeout: Option<Duration>,
+    ) -> Result<EventSet>
         where E: Evented + 'static
     {
+        self.unwind_if_cancelled();
+        let maybe_timeout = try!(self.timeout_before_deadline(maybe_timeout));
+        let io_token = next_io_token();
+        let io_owner = self.home_io_owner();
+        let readiness = self.coroutine.readiness.clone();
+        try!(self.run_on_io_owner(&io_owner, move |mio_event_loop, io_registrations| {
+            register_io(
+                mio_event_loop,
+                io_registrations,
+                io,
+                io_token,
+                interest,
+                opt,
+                &readiness,
+            )
+        }));
+        self.coroutine.io_tokens.insert(io_address(io), io_token);
+
+        self.wait_for_readiness(io_token, maybe_timeout)
+    }
+
+    /// The scheduler this coroutine registers its IO with, which is the one
+    /// running it when it first registers IO
+    fn home_io_owner(&mut self) -> IoOwner {
+        if let Some(ref io_owner) = self.coroutine.io_owner {
+            return io_owner.clone()
+        }
+        let io_owner = self.coroutine.scheduler().io_owner();
+        self.coroutine.io_owner = Some(io_owner.clone());
+
+        io_owner
+    }
+
+    /// If the coroutine is cancelled or times out before the IO is ready the
+    /// IO is left registered, the caller still owns it and decides whether
+    /// to wait again or deregister it
+    fn wait_for_readiness(&mut self, io_token: Token, maybe_timeout: Option<Duration>) -> Result<EventSet> {
+        let readiness = self.coroutine.readiness.clone();
+        match self.park_until_ready(&readiness, io_token, EventSet::all(), maybe_timeout) {
+            Some(eventset) => Ok(eventset),
+            None => {
+                self.unwind_if_cancelled();
+
+                Err(CorosError::TimedOut)
+            },
+        }
+    }
+
+    /// Blocks until readiness matching the interest arrives for the
+    /// registration, returning None if the coroutine was woken by a timeout
+    /// or cancellation instead. Readiness that's already queued is returned
+    /// without suspending.
+    fn park_until_ready(
+        &mut self,
+        readiness: &Arc<Mutex<ReadinessQueue>>,
+        io_token: Token,
+        interest: EventSet,
+        maybe_timeout: Option<Duration>,
+    ) -> Option<EventSet> {
+        if let Some(eventset) = lock_readiness(readiness).take(io_token, interest) {
+            return Some(eventset)
+        }
         self.coroutine.state = CoroutineState::Blocked;
-        let (eventset_tx, eventset_rx) = channel::<EventSet>();
-        let raw_io_ptr: *const E = io as *const E;
+        let waiter_readiness = readiness.clone();
 
-        let mio_callback = move |coroutine: Coroutine,
+        let mio_callback = move |coroutine: Box<Coroutine>,
                                  mio_event_loop: &mut EventLoop<Scheduler>,
                                  blocked_coroutines: &mut BlockedCoroutineSlab| -> Result<()> {
-            let token = match blocked_coroutines.insert((coroutine, Some(eventset_tx))) {
-                Ok(token) => token,
-                Err(_) => return Err(CorosError::SlabFull),
-            };
-            try!(
-                mio_event_loop.register(
-                    unsafe { &*raw_io_ptr },
-                    token,
-                    interest,
-                    opt,
-                )
-            );
-
-            Ok(())
+            let blocked_message = try!(block_coroutine(
+                coroutine,
+                maybe_timeout,
+                mio_event_loop,
+                blocked_coroutines,
+            ));
+
+            lock_readiness(&waiter_readiness).wait(io_token, interest, blocked_message)
         };
 
-        try!(self.suspend_with_callback(Box::new(mio_callback)));
+        self.switch_to_scheduler(Box::new(mio_callback));
+
+        let mut readiness = lock_readiness(readiness);
+        readiness.cancel_wait(self.coroutine.wait_id);
 
-        Ok(try!(eventset_rx.recv()))
+        readiness.take(io_token, interest)
     }
 
-    pub fn deregister<E: ?Sized>(&mut self, io: &E) -> Result<()>
-        where E: Evented + 'static
+    /// Unwinds the coroutine's stack so its destructors run, the coroutine's
+    /// JoinHandle then reports CoroutineCancelled
+    fn unwind_if_cancelled(&mut self) {
+        if !self.is_cancelled() {
+            return
+        }
+        self.coroutine.state = CoroutineState::Running;
+        self.coroutine.event_loop_registration = None;
+
+        panic::propagate(Box::new(CoroutineCancellation));
+    }
+
+    /// Shortens a timeout so it doesn't run past the coroutine's deadline,
+    /// failing if the deadline has already passed
+    fn timeout_before_deadline(&self, maybe_timeout: Option<Duration>) -> Result<Option<Duration>> {
+        let deadline = match self.coroutine.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(maybe_timeout),
+        };
+        let now = time::precise_time_ns();
+        if now >= deadline {
+            return Err(CorosError::TimedOut)
+        }
+        let remaining = nanos_as_duration(deadline - now);
+
+        match maybe_timeout {
+            Some(timeout) => Ok(Some(cmp::min(timeout, remaining))),
+            None => Ok(Some(remaining)),
+        }
+    }
+
+    fn deadline_has_passed(&self) -> bool {
+        match self.coroutine.deadline {
+            Some(deadline) => time::precise_time_ns() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Blocks the coroutine until it's woken through its scheduler's notify
+    /// handler, or until the timeout or the coroutine's deadline passes. The
+    /// park callback is handed the BlockedMessage needed to wake the
+    /// coroutine once it's been suspended.
+    fn park_with_timeout<F>(&mut self, maybe_timeout: Option<Duration>, park_callback: F) -> Result<()>
+        where F: FnOnce(BlockedMessage) -> Result<()> + 'static
+    {
+        let park_result = self.park_until_woken(maybe_timeout, park_callback);
+        self.unwind_if_cancelled();
+
+        park_result
+    }
+
+    /// Like park_with_timeout, but a coroutine cancelled while it's parked is
+    /// returned to the caller before unwinding, so that it can remove itself
+    /// from whatever it was parked on first
+    fn park_until_woken<F>(&mut self, maybe_timeout: Option<Duration>, park_callback: F) -> Result<()>
+        where F: FnOnce(BlockedMessage) -> Result<()> + 'static
     {
+        self.unwind_if_cancelled();
+        let maybe_timeout = try!(self.timeout_before_deadline(maybe_timeout));
         self.coroutine.state = CoroutineState::Blocked;
-        let raw_io_ptr: *const E = io as *const E;
 
-        let mio_callback = move |coroutine: Coroutine,
+        let mio_callback = move |coroutine: Box<Coroutine>,
                                  mio_event_loop: &mut EventLoop<Scheduler>,
                                  blocked_coroutines: &mut BlockedCoroutineSlab| -> Result<()> {
-            let token = match blocked_coroutines.insert((coroutine, None)) {
-                Ok(token) => token,
-                Err(_) => return Err(CorosError::SlabFull),
-            };
-            try!(mio_event_loop.deregister(unsafe { &*raw_io_ptr }));
-            try!(mio_event_loop.timeout(token, Duration::new(0, 0)));
+            let blocked_message = try!(block_coroutine(
+                coroutine,
+                maybe_timeout,
+                mio_event_loop,
+                blocked_coroutines,
+            ));
+
+            park_callback(blocked_message)
+        };
+
+        self.switch_to_scheduler(Box::new(mio_callback));
 
-            Ok(())
+        if self.coroutine.timed_out {
+            return Err(CorosError::TimedOut)
+        }
+
+        Ok(())
+    }
+
+    /// Runs the operation against the event loop of the scheduler that owns
+    /// the IO. If that isn't the scheduler running the coroutine, the
+    /// operation is sent to the owner and the coroutine waits for it to run
+    /// there. The wait can't be cut short by cancellation, since the
+    /// operation borrows from the coroutine's stack, but it fails with
+    /// IoOwnerShutDown if the owner shuts down before running it.
+    fn run_on_io_owner<'o, F>(&mut self, io_owner: &IoOwner, operation: F) -> Result<()>
+        where F: FnOnce(&mut EventLoop<Scheduler>, &IoRegistrations) -> Result<()> + 'o
+    {
+        if io_owner.scheduler_id() == self.coroutine.scheduler().id() {
+            return self.coroutine.scheduler_mut().run_io_operation(operation)
+        }
+
+        let operation_result = Arc::new(Mutex::new(OperationResult {
+            maybe_blocked_message: None,
+            maybe_result: None,
+        }));
+        let operation: IoOperation<'o> = Box::new(operation);
+        // Extending the operation's borrows to 'static is sound because this
+        // function doesn't return until the operation has left a result, and
+        // it only leaves one once it has run or been dropped. The owner drops
+        // the operations it still has queued when it shuts down, including
+        // when its thread panics, and the wait below ignores cancellation and
+        // deadlines, so the coroutine can't unwind out from under the
+        // borrows. The coroutine itself can't be dropped while it waits, its
+        // scheduler doesn't shut down while it has blocked coroutines.
+        let borrowing_operation = BorrowingOperation(unsafe { mem::transmute(operation) });
+        let mut completion = OperationCompletion {
+            maybe_operation_result: Some(operation_result.clone()),
         };
+        try!(io_owner.send(Box::new(move |mio_event_loop: &mut EventLoop<Scheduler>,
+                                         io_registrations: &IoRegistrations| {
+            let BorrowingOperation(operation) = borrowing_operation;
+            completion.complete(operation.call_box((mio_event_loop, io_registrations)));
+        })));
+
+        loop {
+            if let Some(result) = lock_operation_result(&operation_result).maybe_result.take() {
+                return result
+            }
 
-        Ok(try!(self.suspend_with_callback(Box::new(mio_callback))))
+            let waiter_operation_result = operation_result.clone();
+            self.park_uninterruptibly(move |blocked_message| {
+                let mut operation_result = lock_operation_result(&waiter_operation_result);
+                if operation_result.maybe_result.is_some() {
+                    return blocked_message.wake()
+                }
+                operation_result.maybe_blocked_message = Some(blocked_message);
+
+                Ok(())
+            });
+        }
     }
 
-    pub fn reregister<E: ?Sized>(&mut self, io: &E, interest: EventSet, opt: PollOpt) -> Result<EventSet>
-        where E: Evented + 'static
+    /// Blocks the coroutine until it's woken, ignoring its deadline. A
+    /// cancelled coroutine is woken straight away without unwinding, so the
+    /// caller has to check whatever it's waiting for again.
+    fn park_uninterruptibly<F>(&mut self, park_callback: F)
+        where F: FnOnce(BlockedMessage) -> Result<()> + 'static
     {
         self.coroutine.state = CoroutineState::Blocked;
-        let (eventset_tx, eventset_rx) = channel::<EventSet>();
-        let raw_io_ptr: *const E = io as *const E;
 
-        let mio_callback = move |coroutine: Coroutine,
+        let mio_callback = move |coroutine: Box<Coroutine>,
                                  mio_event_loop: &mut EventLoop<Scheduler>,
                                  blocked_coroutines: &mut BlockedCoroutineSlab| -> Result<()> {
-            let token = match blocked_coroutines.insert((coroutine, Some(eventset_tx))) {
-                Ok(token) => token,
-                Err(_) => return Err(CorosError::SlabFull),
-            };
-            try!(
-                mio_event_loop.reregister(
-                    unsafe { &*raw_io_ptr },
-                    token,
-                    interest,
-                    opt,
-                )
-            );
-
-            Ok(())
+            let blocked_message = try!(block_coroutine(
+                coroutine,
+                None,
+                mio_event_loop,
+                blocked_coroutines,
+            ));
+
+            park_callback(blocked_message)
         };
 
-        try!(self.suspend_with_callback(Box::new(mio_callback)));
-
-        Ok(try!(eventset_rx.recv()))
+        self.switch_to_scheduler(Box::new(mio_callback));
     }
 
-    fn suspend_with_callback(&mut self, event_loop_registration: EventLoopRegistrationCallback) -> Result<()> {
+    fn switch_to_scheduler(&mut self, event_loop_registration: EventLoopRegistrationCallback) {
         self.coroutine.event_loop_registration = Some(event_loop_registration);
 
-        Context::swap(&self.coroutine.context, self.scheduler_context);
-
-        Ok(())
+        Context::swap(self.coroutine.context(), self.coroutine.scheduler_context());
     }
 }
 
+fn poll_wait_specs<M: Send>(wait_specs: &[WaitSpec<M>]) -> Option<Selected<M>> {
+    wait_specs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, wait_spec)| wait_spec.poll(index))
+        .next()
+}
+
+fn lock_readiness(readiness: &Mutex<ReadinessQueue>) -> MutexGuard<ReadinessQueue> {
+    readiness
+        .lock()
+        .expect("Coros internal error: readiness queue lock poisoned")
+}
+
+fn lock_operation_result(operation_result: &Mutex<OperationResult>) -> MutexGuard<OperationResult> {
+    operation_result
+        .lock()
+        .expect("Coros internal error: operation result lock poisoned")
+}
+
+/// IO registered through an IoHandle is looked up by its address, since
+/// Evented doesn't give it any other identity
+fn io_address<E: ?Sized>(io: &E) -> usize {
+    io as *const E as *const () as usize
+}
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+fn duration_as_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * NANOS_PER_SEC + duration.subsec_nanos() as u64
+}
+
+fn nanos_as_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / NANOS_PER_SEC, (nanos % NANOS_PER_SEC) as u32)
+}
+
 impl<'_> RecoverSafe for IoHandle<'_> {}
 impl<'_> RefRecoverSafe for IoHandle<'_> {}
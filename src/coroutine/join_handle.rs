@@ -1,24 +1,140 @@
+use std::sync::{
+    Arc,
+    Mutex,
+};
 use std::sync::mpsc::Receiver;
 
+use coroutine::channel::BlockedMessage;
 use error::CorosError;
 use Result;
 
+/// Shared between a coroutine and its JoinHandle so that coroutines joining
+/// or selecting on it can park until it finishes instead of blocking their
+/// threads
+pub struct JoinWaiter {
+    is_finished: bool,
+    blocked_messages: Vec<BlockedMessage>,
+    pub stack_high_water_mark: Option<usize>,
+}
+
+impl JoinWaiter {
+    pub fn new() -> JoinWaiter {
+        JoinWaiter {
+            is_finished: false,
+            blocked_messages: Vec::new(),
+            stack_high_water_mark: None,
+        }
+    }
+
+    /// Called once the coroutine's result has been sent, wakes every
+    /// coroutine waiting for it. Failing to wake one doesn't stop the rest
+    /// being woken.
+    pub fn finish(&mut self) -> Result<()> {
+        self.is_finished = true;
+
+        let mut result = Ok(());
+        for blocked_message in self.blocked_messages.drain(..) {
+            if let Err(err) = blocked_message.wake() {
+                result = Err(err);
+            }
+        }
+
+        result
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    pub fn wait(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.is_finished {
+            return blocked_message.wake()
+        }
+        self.blocked_messages.push(blocked_message);
+
+        Ok(())
+    }
+
+    /// Removes a waiter that was woken by something else, such as a timeout
+    /// or another select arm
+    pub fn cancel_wait(&mut self, wait_id: usize) {
+        self.blocked_messages.retain(|blocked_message| blocked_message.wait_id != wait_id);
+    }
+}
+
+/// Continues a joined coroutine's panic, passing any other result through
+pub fn resume_panic<T>(result: Result<T>) -> Result<T> {
+    match result {
+        Err(CorosError::CoroutinePanic(details)) => details.resume(),
+        result => result,
+    }
+}
+
+/// Panic payload used to unwind the stack of a cancelled coroutine
+pub struct CoroutineCancellation;
+
+/// Shared between a coroutine and its JoinHandle so the handle can find and
+/// wake the coroutine wherever it's parked when it's aborted
+pub struct Cancellation {
+    pub is_cancelled: bool,
+    parked: Option<BlockedMessage>,
+}
+
+impl Cancellation {
+    pub fn new() -> Cancellation {
+        Cancellation {
+            is_cancelled: false,
+            parked: None,
+        }
+    }
+
+    pub fn cancel(&mut self) -> Result<()> {
+        self.is_cancelled = true;
+
+        match self.parked.take() {
+            Some(blocked_message) => blocked_message.wake(),
+            None => Ok(()),
+        }
+    }
+
+    /// Called every time the coroutine parks
+    pub fn park(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.is_cancelled {
+            return blocked_message.wake()
+        }
+        self.parked = Some(blocked_message);
+
+        Ok(())
+    }
+}
+
 pub struct JoinHandle<T>
     where T: Send + 'static
 {
+    cancellation: Arc<Mutex<Cancellation>>,
+    coroutine_id: usize,
     coroutine_result_rx: Receiver<Result<T>>,
     pub is_joined: bool,
+    pub join_waiter: Arc<Mutex<JoinWaiter>>,
 }
 
 impl<T> JoinHandle<T>
     where T: Send + 'static
 {
 
-    pub fn new(coroutine_result_rx: Receiver<Result<T>>) -> JoinHandle<T>
+    pub fn new(
+        coroutine_id: usize,
+        cancellation: Arc<Mutex<Cancellation>>,
+        coroutine_result_rx: Receiver<Result<T>>,
+        join_waiter: Arc<Mutex<JoinWaiter>>,
+    ) -> JoinHandle<T>
     {
         JoinHandle {
+            cancellation: cancellation,
+            coroutine_id: coroutine_id,
             coroutine_result_rx: coroutine_result_rx,
             is_joined: false,
+            join_waiter: join_waiter,
         }
     }
 
@@ -30,4 +146,34 @@ impl<T> JoinHandle<T>
 
         Ok(try!(self.coroutine_result_rx.recv()))
     }
+
+    /// Joins the coroutine, continuing its panic on this thread if its body
+    /// panicked, so assertion failures inside coroutines fail tests as usual
+    pub fn join_resuming_panic(&mut self) -> Result<T> {
+        resume_panic(try!(self.join()))
+    }
+
+    /// The id the coroutine is identified by in CoroutinePanic errors
+    pub fn coroutine_id(&self) -> usize {
+        self.coroutine_id
+    }
+
+    /// How many bytes of its stack the coroutine used, available once it
+    /// has finished if its pool was built with measure_stack_usage
+    pub fn stack_high_water_mark(&self) -> Option<usize> {
+        self.join_waiter
+            .lock()
+            .expect("Coros internal error: join waiter lock poisoned")
+            .stack_high_water_mark
+    }
+
+    /// Cancels the coroutine, waking it if it's blocked. Its stack is unwound
+    /// the next time it runs, and joining it returns CoroutineCancelled.
+    /// Aborting a coroutine that has already finished has no effect.
+    pub fn abort(&self) -> Result<()> {
+        self.cancellation
+            .lock()
+            .expect("Coros internal error: cancellation lock poisoned")
+            .cancel()
+    }
 }
@@ -1,57 +1,140 @@
-use std::borrow::Borrow;
 use std::boxed::FnBox;
-use std::mem;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::panic;
+use std::ptr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::atomic::{
+    ATOMIC_USIZE_INIT,
+    AtomicUsize,
+    Ordering,
+};
+use std::sync::mpsc;
 
-use context::{
-    Context,
-    Stack,
+use context::Context;
+use mio::{
+    EventLoop,
+    Token,
 };
-use mio::EventLoop;
+use mio::Timeout as MioTimeout;
 
 pub mod io_handle;
 pub mod join_handle;
+pub mod broadcast;
 pub mod channel;
+pub mod oneshot;
+pub mod readiness;
+pub mod registration;
+pub mod select;
+pub mod waiter;
+pub mod watch;
 
 use IoHandle;
+use JoinHandle;
 use Result;
+use coroutine::join_handle::{
+    Cancellation,
+    CoroutineCancellation,
+    JoinWaiter,
+};
+use coroutine::readiness::ReadinessQueue;
+use error::{
+    CoroutinePanicDetails,
+    CorosError,
+};
 use scheduler::{
     BlockedCoroutineSlab,
+    IoOwner,
     Scheduler,
 };
+use stack::{
+    CoroutineStack,
+    GuardPage,
+};
+use stack_cache::size_class;
 
 #[derive(Debug)]
 pub enum CoroutineState {
     New,
     Running,
     Blocked,
+    Yielded,
+    Finished,
+}
+
+/// The coroutine each scheduler thread is running, set before every switch
+/// into a coroutine's context
+thread_local!(static RUNNING_COROUTINE: Cell<*mut Coroutine> = Cell::new(ptr::null_mut()));
+
+fn running_coroutine<'a>() -> &'a mut Coroutine {
+    RUNNING_COROUTINE.with(|running_coroutine| unsafe { &mut *running_coroutine.get() })
 }
 
-extern "C" fn context_init(coroutine_ptr: usize, scheduler_context_ptr: usize) -> ! {
-    let coroutine: &mut Coroutine = unsafe { mem::transmute(coroutine_ptr) };
-    let function = coroutine
-        .function
-        .take()
-        .expect("Coros internal error: cannot run coroutine without function");
-    let scheduler_context: &Context = unsafe { mem::transmute(scheduler_context_ptr) };
-    let coroutine_blocking_handle = IoHandle {
-        coroutine: coroutine,
-        scheduler_context: scheduler_context,
-    };
+/// The coroutine running on this thread, if any. Read by the stack overflow
+/// handler, so it mustn't allocate or lock.
+pub fn maybe_running_coroutine<'a>() -> Option<&'a Coroutine> {
+    RUNNING_COROUTINE.with(|running_coroutine| unsafe { running_coroutine.get().as_ref() })
+}
 
-    function.call_box((coroutine_blocking_handle,));
+/// Runs coroutine bodies one after another on the same stack. Once a body
+/// finishes the context is left parked at the end of the loop, so a
+/// scheduler can cache it with its stack and resume it for a new coroutine,
+/// which is why the coroutine isn't passed in as an argument.
+extern "C" fn context_init(_: usize, _: usize) -> ! {
+    loop {
+        let coroutine = running_coroutine();
+        let function = coroutine
+            .function
+            .take()
+            .expect("Coros internal error: cannot run coroutine without function");
+        let coroutine_blocking_handle = IoHandle {
+            coroutine: coroutine,
+        };
 
-    Context::load(&scheduler_context);
+        function.call_box((coroutine_blocking_handle,));
 
-    unreachable!("Coros internal error: execution should never reach here");
+        let coroutine = running_coroutine();
+        coroutine.state = CoroutineState::Finished;
+        Context::swap(coroutine.context(), coroutine.scheduler_context());
+    }
 }
 
-pub type EventLoopRegistrationCallback = Box<FnBox(Coroutine, &mut EventLoop<Scheduler>, &mut BlockedCoroutineSlab) -> Result<()>>;
+/// Coroutine ids are unique across all pools, they identify coroutines in
+/// errors and logs
+static NEXT_COROUTINE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub type EventLoopRegistrationCallback = Box<FnBox(Box<Coroutine>, &mut EventLoop<Scheduler>, &mut BlockedCoroutineSlab) -> Result<()>>;
 
+/// Coroutines are always boxed so that the IoHandle living on the
+/// coroutine's stack keeps pointing at the right place after the coroutine
+/// is moved between the work queue and the blocked slab, or stolen by
+/// another thread
 pub struct Coroutine {
-    pub context: Context,
+    pub cancellation: Arc<Mutex<Cancellation>>,
+    /// Precise time in nanoseconds after which blocking operations time out
+    pub deadline: Option<u64>,
     function: Option<Box<FnBox(IoHandle) + Send + 'static>>,
     pub event_loop_registration: Option<EventLoopRegistrationCallback>,
+    pub id: usize,
+    /// The scheduler whose event loop this coroutine registers its IO with,
+    /// the one it first registered IO on. It doesn't change when the
+    /// coroutine is stolen, so IO is always reregistered and deregistered
+    /// through the event loop it was registered with.
+    pub io_owner: Option<IoOwner>,
+    /// Registration tokens of the IO this coroutine has registered, keyed by
+    /// the IO's address
+    pub io_tokens: HashMap<usize, Token>,
+    pub readiness: Arc<Mutex<ReadinessQueue>>,
+    scheduler: *mut Scheduler,
+    stack: Option<CoroutineStack>,
+    pub stack_size: usize,
     pub state: CoroutineState,
+    pub timed_out: bool,
+    pub timer: Option<MioTimeout>,
+    pub wait_id: usize,
 }
 
 impl Clone for Coroutine {
@@ -63,41 +146,158 @@ impl Clone for Coroutine {
 unsafe impl Send for Coroutine {}
 
 impl Coroutine {
+    /// The coroutine's stack isn't allocated until it first runs, so that
+    /// the scheduler running it can reuse a cached one
     pub fn new(
         function: Box<FnBox(IoHandle) + Send + 'static>,
-        stack: Stack,
-    ) -> Coroutine
+        stack_size: usize,
+    ) -> Box<Coroutine>
     {
-        let context = Context::new(
-            context_init,
-            0 as usize,
-            0 as usize,
-            stack,
-        );
         let coroutine = Coroutine {
-            context: context,
+            cancellation: Arc::new(Mutex::new(Cancellation::new())),
+            deadline: None,
             function: Some(function),
             event_loop_registration: None,
+            id: NEXT_COROUTINE_ID.fetch_add(1, Ordering::SeqCst),
+            io_owner: None,
+            io_tokens: HashMap::new(),
+            readiness: Arc::new(Mutex::new(ReadinessQueue::new())),
+            scheduler: ptr::null_mut(),
+            stack: None,
+            stack_size: stack_size,
             state: CoroutineState::New,
+            timed_out: false,
+            timer: None,
+            wait_id: 0,
         };
 
-        coroutine
+        Box::new(coroutine)
+    }
+
+    /// Wraps a coroutine body so that its result, or its panic, is sent to
+    /// the returned JoinHandle once the body finishes
+    pub fn with_join_handle<F, T>(
+        coroutine_body: F,
+        stack_size: usize,
+    ) -> (Box<Coroutine>, JoinHandle<T>)
+        where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        let (coroutine_result_tx, coroutine_result_rx) = mpsc::channel();
+        let join_waiter = Arc::new(Mutex::new(JoinWaiter::new()));
+        let coroutine_join_waiter = join_waiter.clone();
+        let coroutine_function = Box::new(move |coroutine_handle: IoHandle| {
+            let coroutine_id = coroutine_handle.coroutine.id;
+            let result = if coroutine_handle.is_cancelled() {
+                Err(CorosError::CoroutineCancelled)
+            } else {
+                let maybe_coroutine_result = panic::recover(move || {
+                    coroutine_body(coroutine_handle)
+                });
+
+                match maybe_coroutine_result {
+                    Ok(coroutine_result) => Ok(coroutine_result),
+                    Err(ref err) if err.is::<CoroutineCancellation>() => {
+                        Err(CorosError::CoroutineCancelled)
+                    },
+                    Err(payload) => {
+                        let details = CoroutinePanicDetails {
+                            coroutine_id: coroutine_id,
+                            payload: payload,
+                        };
+                        error!("Coroutine body panicked: {:?}", details);
+                        Err(CorosError::CoroutinePanic(details))
+                    },
+                }
+            };
+
+            // Recorded before the result is sent so it's there once the
+            // coroutine has been joined
+            let stack_high_water_mark = running_coroutine().measure_stack_usage();
+            coroutine_join_waiter
+                .lock()
+                .expect("Coros internal error: join waiter lock poisoned")
+                .stack_high_water_mark = stack_high_water_mark;
+            coroutine_result_tx
+                .send(result)
+                .expect("Coros internal error: attempting to send coroutine result to closed channel");
+
+            let finish_result = coroutine_join_waiter
+                .lock()
+                .expect("Coros internal error: join waiter lock poisoned")
+                .finish();
+            if let Err(err) = finish_result {
+                error!("Error waking coroutine joining finished coroutine: {:?}", err);
+            }
+        });
+
+        let coroutine = Coroutine::new(
+            coroutine_function,
+            stack_size,
+        );
+        let join_handle = JoinHandle::<T>::new(
+            coroutine.id,
+            coroutine.cancellation.clone(),
+            coroutine_result_rx,
+            join_waiter,
+        );
+
+        (coroutine, join_handle)
     }
 
-    pub fn raw_pointer(&self) -> *const Coroutine {
-        self.borrow() as *const Coroutine
+    /// The scheduler currently running this coroutine. Only valid while the
+    /// coroutine is running, since a coroutine can be stolen by a different
+    /// scheduler every time it's requeued.
+    pub fn scheduler(&self) -> &Scheduler {
+        unsafe { &*self.scheduler }
     }
 
-    pub fn run(&mut self, scheduler_context: &Context) -> Result<()> {
-        try!(self.set_context_to_run_coroutine());
-        try!(self.set_context_to_return_to_scheduler(scheduler_context));
+    /// Mutable access to the scheduler is only safe from inside the running
+    /// coroutine, while the scheduler is suspended in run_coroutine
+    pub fn scheduler_mut(&self) -> &mut Scheduler {
+        unsafe { &mut *self.scheduler }
+    }
+
+    pub fn scheduler_context(&self) -> &Context {
+        self.scheduler().scheduler_context()
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.stack
+            .as_ref()
+            .expect("Coros internal error: coroutine has no stack")
+            .context
+    }
+
+    pub fn guard_page(&self) -> Option<&GuardPage> {
+        self.stack.as_ref().map(|stack| stack.guard_page())
+    }
+
+    /// Switches to the coroutine until it next blocks, yields or finishes.
+    /// It's recorded as the thread's running coroutine for the duration, so
+    /// the stack overflow handler can find its guard page. A new coroutine
+    /// takes a stack from the scheduler's cache first.
+    pub fn run(&mut self, scheduler: *mut Scheduler) -> Result<()> {
+        self.scheduler = scheduler;
+        if let CoroutineState::New = self.state {
+            self.stack = Some(try!(self.start_stack()));
+        }
         self.state = CoroutineState::Running;
+        let raw_self_ptr: *mut Coroutine = self;
+        RUNNING_COROUTINE.with(|running_coroutine| running_coroutine.set(raw_self_ptr));
 
-        Context::swap(scheduler_context, &self.context);
+        Context::swap(self.scheduler_context(), self.context());
+        RUNNING_COROUTINE.with(|running_coroutine| running_coroutine.set(ptr::null_mut()));
 
         Ok(())
     }
 
+    /// Hands back the finished coroutine's stack, along with the context
+    /// parked on it, for the scheduler to cache
+    pub fn take_stack(&mut self) -> Option<CoroutineStack> {
+        self.stack.take()
+    }
+
     pub fn blocked(&self) -> bool {
         match self.state {
             CoroutineState::Blocked => true,
@@ -105,22 +305,49 @@ impl Coroutine {
         }
     }
 
-    /// Moving ownership of a value can change it's memory address. The first
-    /// argument to the context_init, the pointer back to the calling coroutine,
-    /// needs to be updated before coroutine is run in case the coroutine has
-    /// been moved.
-    fn set_context_to_run_coroutine(&mut self) -> Result<()> {
-        let raw_self_ptr = self.raw_pointer();
+    pub fn yielded(&self) -> bool {
+        match self.state {
+            CoroutineState::Yielded => true,
+            _ => false,
+        }
+    }
 
-        try!(self.context.set_arg(raw_self_ptr as usize, 0));
+    pub fn finished(&self) -> bool {
+        match self.state {
+            CoroutineState::Finished => true,
+            _ => false,
+        }
+    }
 
-        Ok(())
+    /// A cached stack from the scheduler if it has one big enough, otherwise
+    /// a new one. Stacks are painted if the scheduler is measuring stack
+    /// usage, in which case they're never cached.
+    fn start_stack(&self) -> Result<CoroutineStack> {
+        match self.scheduler_mut().stack_cache_mut().take(self.stack_size) {
+            Some(stack) => Ok(stack),
+            None => CoroutineStack::new(
+                context_init,
+                0 as usize,
+                size_class(self.stack_size),
+                self.scheduler().stack_usage().is_some(),
+            ),
+        }
     }
 
-    fn set_context_to_return_to_scheduler(&mut self, scheduler_context: &Context) -> Result<()> {
-        let scheduler_context_ptr = scheduler_context.borrow() as *const Context;
-        try!(self.context.set_arg(scheduler_context_ptr as usize, 1));
+    /// How deep the coroutine's stack got, if its scheduler is measuring
+    /// stack usage. Also recorded in the pool's histogram.
+    fn measure_stack_usage(&self) -> Option<usize> {
+        let maybe_high_water_mark = self.stack
+            .as_ref()
+            .and_then(|stack| stack.high_water_mark());
+        let maybe_stack_usage = self.scheduler().stack_usage();
+        if let (Some(high_water_mark), Some(stack_usage)) = (maybe_high_water_mark, maybe_stack_usage) {
+            stack_usage
+                .lock()
+                .expect("Coros internal error: stack usage lock poisoned")
+                .record(high_water_mark);
+        }
 
-        Ok(())
+        maybe_high_water_mark
     }
 }
@@ -0,0 +1,179 @@
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+    unless_empty,
+};
+use error::CorosError;
+use Result;
+
+/// The single value and the receiver parked waiting for it
+struct Oneshot<T: Send> {
+    is_receiver_dropped: bool,
+    is_sender_dropped: bool,
+    parked_receiver: Option<BlockedMessage>,
+    value: Option<T>,
+}
+
+fn lock_oneshot<T: Send>(oneshot: &Mutex<Oneshot<T>>) -> MutexGuard<Oneshot<T>> {
+    oneshot
+        .lock()
+        .expect("Coros internal error: oneshot lock poisoned")
+}
+
+fn wake(maybe_blocked_message: Option<BlockedMessage>) {
+    if let Some(blocked_message) = maybe_blocked_message {
+        if let Err(err) = blocked_message.wake() {
+            error!("Error waking coroutine parked on oneshot: {:?}", err);
+        }
+    }
+}
+
+/// Sends a single value, the sender is consumed by sending
+pub struct OneshotSender<T: Send> {
+    oneshot: Arc<Mutex<Oneshot<T>>>,
+}
+
+impl<T: Send> OneshotSender<T> {
+    /// Returns ChannelClosed if the receiver has already been dropped
+    pub fn send(self, value: T) -> Result<()> {
+        let maybe_parked_receiver = {
+            let mut oneshot = lock_oneshot(&self.oneshot);
+            if oneshot.is_receiver_dropped {
+                return Err(CorosError::ChannelClosed)
+            }
+            oneshot.value = Some(value);
+
+            oneshot.parked_receiver.take()
+        };
+        wake(maybe_parked_receiver);
+
+        Ok(())
+    }
+}
+
+/// Dropping the sender without sending wakes the receiver with
+/// ChannelClosed
+impl<T: Send> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let maybe_parked_receiver = match self.oneshot.lock() {
+            Ok(mut oneshot) => {
+                oneshot.is_sender_dropped = true;
+
+                oneshot.parked_receiver.take()
+            },
+            Err(_) => return,
+        };
+        wake(maybe_parked_receiver);
+    }
+}
+
+impl<T: Send> panic::RecoverSafe for OneshotSender<T> {}
+impl<T: Send> panic::RefRecoverSafe for OneshotSender<T> {}
+
+/// Received from inside a coroutine with IoHandle::recv_oneshot
+pub struct OneshotReceiver<T: Send> {
+    oneshot: Arc<Mutex<Oneshot<T>>>,
+}
+
+impl<T: Send> OneshotReceiver<T> {
+    /// Returns ChannelEmpty if the value hasn't been sent yet, or
+    /// ChannelClosed if the sender was dropped without sending one
+    pub fn try_recv(&mut self) -> Result<T> {
+        let (maybe_value, is_sender_dropped) = {
+            let mut oneshot = lock_oneshot(&self.oneshot);
+            let maybe_value = oneshot.value.take();
+
+            (maybe_value, oneshot.is_sender_dropped)
+        };
+
+        match maybe_value {
+            Some(value) => Ok(value),
+            None if is_sender_dropped => Err(CorosError::ChannelClosed),
+            None => Err(CorosError::ChannelEmpty),
+        }
+    }
+
+    /// Builds the callback that parks a coroutine until the value is sent
+    pub fn park_callback(&self) -> ParkCallback
+        where T: 'static
+    {
+        let oneshot = self.oneshot.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            let mut oneshot = lock_oneshot(&oneshot);
+            if oneshot.value.is_some() || oneshot.is_sender_dropped {
+                return blocked_message.wake()
+            }
+            oneshot.parked_receiver = Some(blocked_message);
+
+            Ok(())
+        })
+    }
+
+    /// Called by a coroutine parked with park_callback once it's running
+    /// again, in case it was woken by something other than the sender
+    pub fn finish_wait(&self, wait_id: usize) {
+        let mut oneshot = lock_oneshot(&self.oneshot);
+        let is_parked = match oneshot.parked_receiver {
+            Some(ref blocked_message) => blocked_message.wait_id == wait_id,
+            None => false,
+        };
+        if is_parked {
+            oneshot.parked_receiver = None;
+        }
+    }
+}
+
+impl<T: Send + 'static> Waitable for OneshotReceiver<T> {
+    type Item = T;
+
+    fn try_take(&mut self) -> Option<Result<T>> {
+        unless_empty(self.try_recv())
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        OneshotReceiver::park_callback(self)
+    }
+
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        OneshotReceiver::finish_wait(self, wait_id);
+    }
+}
+
+impl<T: Send> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        if let Ok(mut oneshot) = self.oneshot.lock() {
+            oneshot.is_receiver_dropped = true;
+        }
+    }
+}
+
+impl<T: Send> panic::RecoverSafe for OneshotReceiver<T> {}
+impl<T: Send> panic::RefRecoverSafe for OneshotReceiver<T> {}
+
+/// A channel for sending a single value, lighter than a full channel since
+/// there's only ever one message and one receiver
+pub fn oneshot<T: Send>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let oneshot = Arc::new(Mutex::new(Oneshot {
+        is_receiver_dropped: false,
+        is_sender_dropped: false,
+        parked_receiver: None,
+        value: None,
+    }));
+    let tx = OneshotSender {
+        oneshot: oneshot.clone(),
+    };
+    let rx = OneshotReceiver {
+        oneshot: oneshot,
+    };
+
+    (tx, rx)
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use mio::{
+    EventSet,
+    Token,
+};
+
+use coroutine::channel::BlockedMessage;
+use Result;
+
+/// IO readiness that has arrived for a coroutine's registrations, keyed by
+/// registration token. Readiness that arrives while the coroutine is running
+/// or blocked on something else builds up here until it next waits on that
+/// registration.
+pub struct ReadinessQueue {
+    pending: HashMap<Token, EventSet>,
+    waiters: Vec<(Token, EventSet, BlockedMessage)>,
+}
+
+impl ReadinessQueue {
+    pub fn new() -> ReadinessQueue {
+        ReadinessQueue {
+            pending: HashMap::new(),
+            waiters: Vec::new(),
+        }
+    }
+
+    /// Called by the scheduler whose event loop the registration belongs to,
+    /// wakes the coroutine if it's waiting on this registration
+    pub fn push(&mut self, token: Token, eventset: EventSet) -> Result<()> {
+        {
+            let pending_eventset = self.pending
+                .entry(token)
+                .or_insert(EventSet::none());
+            *pending_eventset = *pending_eventset | eventset;
+        }
+
+        let maybe_position = self.waiters.iter().position(|&(waiting_token, interest, _)| {
+            waiting_token == token && matches_interest(eventset, interest)
+        });
+        let blocked_message = match maybe_position {
+            Some(position) => self.waiters.remove(position).2,
+            None => return Ok(()),
+        };
+        self.cancel_wait(blocked_message.wait_id);
+
+        blocked_message.wake()
+    }
+
+    /// Takes the pending readiness that matches the interest, leaving the rest
+    /// queued for a later wait
+    pub fn take(&mut self, token: Token, interest: EventSet) -> Option<EventSet> {
+        let pending_eventset = match self.pending.get(&token) {
+            Some(&pending_eventset) => pending_eventset,
+            None => return None,
+        };
+        if !matches_interest(pending_eventset, interest) {
+            return None
+        }
+
+        let taken_eventset = pending_eventset & interest_with_errors(interest);
+        let remaining_eventset = pending_eventset - taken_eventset;
+        if remaining_eventset == EventSet::none() {
+            self.pending.remove(&token);
+        } else {
+            self.pending.insert(token, remaining_eventset);
+        }
+
+        Some(taken_eventset)
+    }
+
+    pub fn wait(&mut self, token: Token, interest: EventSet, blocked_message: BlockedMessage) -> Result<()> {
+        let is_ready = match self.pending.get(&token) {
+            Some(&pending_eventset) => matches_interest(pending_eventset, interest),
+            None => false,
+        };
+        if is_ready {
+            return blocked_message.wake()
+        }
+        self.waiters.push((token, interest, blocked_message));
+
+        Ok(())
+    }
+
+    /// Drops the waiters left behind by a coroutine woken by something other
+    /// than readiness
+    pub fn cancel_wait(&mut self, wait_id: usize) {
+        self.waiters.retain(|&(_, _, ref blocked_message)| blocked_message.wait_id != wait_id);
+    }
+
+    pub fn remove(&mut self, token: Token) {
+        self.pending.remove(&token);
+        self.waiters.retain(|&(waiting_token, _, _)| waiting_token != token);
+    }
+}
+
+/// Errors and hangups are always delivered, whatever the interest
+fn interest_with_errors(interest: EventSet) -> EventSet {
+    interest | EventSet::error() | EventSet::hup()
+}
+
+fn matches_interest(eventset: EventSet, interest: EventSet) -> bool {
+    eventset & interest_with_errors(interest) != EventSet::none()
+}
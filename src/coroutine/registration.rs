@@ -0,0 +1,143 @@
+use std::panic::{RecoverSafe, RefRecoverSafe};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+use mio::{
+    EventLoop,
+    EventSet,
+    Evented,
+    Token,
+};
+
+use coroutine;
+use coroutine::io_handle::IoHandle;
+use coroutine::readiness::ReadinessQueue;
+use scheduler::{
+    IoOwner,
+    IoRegistrations,
+    Scheduler,
+    deregister_io,
+};
+use Result;
+
+/// An IO source registered with a scheduler's event loop, returned by
+/// IoHandle::register_source. The source is registered edge triggered for all
+/// readiness, so it can be waited on any number of times without being
+/// reregistered. As with any edge triggered registration the source should be
+/// read or written until it would block before waiting on it again.
+///
+/// The registration owns the source. Dropping it deregisters the source and
+/// closes it.
+pub struct Registration<E: Evented + Send + 'static> {
+    io_owner: IoOwner,
+    io_token: Token,
+    readiness: Arc<Mutex<ReadinessQueue>>,
+    // Only taken when the registration is dropped
+    source: Option<E>,
+}
+
+impl<E: Evented + Send + 'static> Registration<E> {
+    pub fn new(
+        source: E,
+        io_token: Token,
+        io_owner: IoOwner,
+        readiness: Arc<Mutex<ReadinessQueue>>,
+    ) -> Registration<E> {
+        Registration {
+            io_owner: io_owner,
+            io_token: io_token,
+            readiness: readiness,
+            source: Some(source),
+        }
+    }
+
+    pub fn io_token(&self) -> Token {
+        self.io_token
+    }
+
+    pub fn readiness(&self) -> &Arc<Mutex<ReadinessQueue>> {
+        &self.readiness
+    }
+
+    pub fn get_ref(&self) -> &E {
+        self.source
+            .as_ref()
+            .expect("Coros internal error: registration has no source")
+    }
+
+    pub fn get_mut(&mut self) -> &mut E {
+        self.source
+            .as_mut()
+            .expect("Coros internal error: registration has no source")
+    }
+
+    /// Suspends the coroutine until the source is ready for any of the
+    /// interest, errors and hangups are always returned
+    pub fn wait(&self, coroutine_handle: &mut IoHandle, interest: EventSet) -> Result<EventSet> {
+        coroutine_handle.wait(self, interest)
+    }
+
+    pub fn wait_readable(&self, coroutine_handle: &mut IoHandle) -> Result<EventSet> {
+        self.wait(coroutine_handle, EventSet::readable())
+    }
+
+    pub fn wait_writable(&self, coroutine_handle: &mut IoHandle) -> Result<EventSet> {
+        self.wait(coroutine_handle, EventSet::writable())
+    }
+}
+
+/// The source is deregistered from the event loop it was registered with
+/// before it's closed. A registration dropped by a coroutine running on the
+/// owning scheduler deregisters straight away, so the source can be
+/// registered again at once. Anywhere else the source is sent to the owning
+/// scheduler to be deregistered and closed there. Registrations can be
+/// dropped while a coroutine unwinds, so errors are logged rather than
+/// panicking.
+impl<E: Evented + Send + 'static> Drop for Registration<E> {
+    fn drop(&mut self) {
+        if let Ok(mut readiness) = self.readiness.lock() {
+            readiness.remove(self.io_token);
+        }
+        let source = match self.source.take() {
+            Some(source) => source,
+            None => return,
+        };
+
+        let io_token = self.io_token;
+        let maybe_owning_coroutine = coroutine::maybe_running_coroutine()
+            .and_then(|coroutine| {
+                if coroutine.scheduler().id() == self.io_owner.scheduler_id() {
+                    Some(coroutine)
+                } else {
+                    None
+                }
+            });
+        let deregister_result = match maybe_owning_coroutine {
+            Some(coroutine) => {
+                coroutine.scheduler_mut().run_io_operation(
+                    |mio_event_loop: &mut EventLoop<Scheduler>, io_registrations: &IoRegistrations| {
+                        deregister_io(mio_event_loop, io_registrations, &source, Some(io_token))
+                    }
+                )
+            },
+            None => {
+                self.io_owner.send(Box::new(
+                    move |mio_event_loop: &mut EventLoop<Scheduler>, io_registrations: &IoRegistrations| {
+                        let result = deregister_io(mio_event_loop, io_registrations, &source, Some(io_token));
+                        if let Err(err) = result {
+                            error!("Error deregistering dropped registration: {:?}", err);
+                        }
+                    }
+                ))
+            },
+        };
+        if let Err(err) = deregister_result {
+            error!("Error deregistering dropped registration: {:?}", err);
+        }
+    }
+}
+
+impl<E: Evented + Send + 'static> RecoverSafe for Registration<E> {}
+impl<E: Evented + Send + 'static> RefRecoverSafe for Registration<E> {}
@@ -0,0 +1,173 @@
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Duration;
+
+use mio::{
+    EventSet,
+    Evented,
+    Token,
+};
+
+use coroutine::channel::{
+    BlockedMessage,
+    Receiver,
+};
+use coroutine::join_handle::{
+    JoinHandle,
+    JoinWaiter,
+};
+use coroutine::readiness::ReadinessQueue;
+use coroutine::registration::Registration;
+use coroutine::waiter::ParkCallback;
+use error::CorosError;
+use Result;
+
+/// One of the things IoHandle::select can wait on. M is the message type of
+/// the channel receives being selected over.
+pub enum WaitSpec<'a, M: Send + 'a> {
+    Io(Arc<Mutex<ReadinessQueue>>, Token, EventSet),
+    Recv(&'a Receiver<M>),
+    Timer(Duration),
+    Join(Arc<Mutex<JoinWaiter>>),
+}
+
+/// Which of the wait specs fired, by its index in the slice passed to select
+#[derive(Debug)]
+pub enum Selected<M: Send> {
+    Io(usize, EventSet),
+    Recv(usize, M),
+    Timer(usize),
+    /// The coroutine has finished, joining it won't block
+    Join(usize),
+    /// Every sender of a receive's channel has been dropped
+    Closed(usize),
+}
+
+impl<M: Send> Selected<M> {
+    /// The index of the wait spec that fired
+    pub fn index(&self) -> usize {
+        match *self {
+            Selected::Io(index, _) => index,
+            Selected::Recv(index, _) => index,
+            Selected::Timer(index) => index,
+            Selected::Join(index) => index,
+            Selected::Closed(index) => index,
+        }
+    }
+}
+
+impl<'a, M: Send + 'a> WaitSpec<'a, M> {
+    pub fn io<E>(registration: &Registration<E>, interest: EventSet) -> WaitSpec<'a, M>
+        where E: Evented + Send + 'static
+    {
+        WaitSpec::Io(registration.readiness().clone(), registration.io_token(), interest)
+    }
+
+    pub fn readable<E>(registration: &Registration<E>) -> WaitSpec<'a, M>
+        where E: Evented + Send + 'static
+    {
+        WaitSpec::io(registration, EventSet::readable())
+    }
+
+    pub fn writable<E>(registration: &Registration<E>) -> WaitSpec<'a, M>
+        where E: Evented + Send + 'static
+    {
+        WaitSpec::io(registration, EventSet::writable())
+    }
+
+    pub fn recv(rx: &'a Receiver<M>) -> WaitSpec<'a, M> {
+        WaitSpec::Recv(rx)
+    }
+
+    pub fn timer(timeout: Duration) -> WaitSpec<'a, M> {
+        WaitSpec::Timer(timeout)
+    }
+
+    pub fn join<T: Send + 'static>(join_handle: &JoinHandle<T>) -> WaitSpec<'a, M> {
+        WaitSpec::Join(join_handle.join_waiter.clone())
+    }
+
+    /// Takes whatever the spec is waiting on if it's already arrived
+    pub fn poll(&self, index: usize) -> Option<Selected<M>> {
+        match *self {
+            WaitSpec::Io(ref readiness, token, interest) => {
+                readiness
+                    .lock()
+                    .expect("Coros internal error: readiness queue lock poisoned")
+                    .take(token, interest)
+                    .map(|eventset| Selected::Io(index, eventset))
+            },
+            WaitSpec::Recv(rx) => {
+                match rx.try_recv() {
+                    Ok(message) => Some(Selected::Recv(index, message)),
+                    Err(CorosError::ChannelClosed) => Some(Selected::Closed(index)),
+                    Err(_) => None,
+                }
+            },
+            WaitSpec::Timer(_) => None,
+            WaitSpec::Join(ref join_waiter) => {
+                let is_finished = join_waiter
+                    .lock()
+                    .expect("Coros internal error: join waiter lock poisoned")
+                    .is_finished();
+
+                if is_finished {
+                    Some(Selected::Join(index))
+                } else {
+                    None
+                }
+            },
+        }
+    }
+
+    /// Builds the callback that hands the parked coroutine's BlockedMessage
+    /// to whatever will wake it. Timers are armed by the scheduler instead.
+    pub fn park_callback(&self) -> Option<ParkCallback>
+        where M: 'static
+    {
+        match *self {
+            WaitSpec::Io(ref readiness, token, interest) => {
+                let readiness = readiness.clone();
+                Some(Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+                    readiness
+                        .lock()
+                        .expect("Coros internal error: readiness queue lock poisoned")
+                        .wait(token, interest, blocked_message)
+                }))
+            },
+            WaitSpec::Recv(rx) => Some(rx.park_callback()),
+            WaitSpec::Timer(_) => None,
+            WaitSpec::Join(ref join_waiter) => {
+                let join_waiter = join_waiter.clone();
+                Some(Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+                    join_waiter
+                        .lock()
+                        .expect("Coros internal error: join waiter lock poisoned")
+                        .wait(blocked_message)
+                }))
+            },
+        }
+    }
+
+    /// Removes the waiters left behind once the coroutine has been woken
+    pub fn finish_wait(&self, wait_id: usize, was_selected: bool) {
+        match *self {
+            WaitSpec::Io(ref readiness, _, _) => {
+                readiness
+                    .lock()
+                    .expect("Coros internal error: readiness queue lock poisoned")
+                    .cancel_wait(wait_id);
+            },
+            WaitSpec::Recv(rx) => rx.finish_wait(wait_id, was_selected),
+            WaitSpec::Timer(_) => {},
+            WaitSpec::Join(ref join_waiter) => {
+                join_waiter
+                    .lock()
+                    .expect("Coros internal error: join waiter lock poisoned")
+                    .cancel_wait(wait_id);
+            },
+        }
+    }
+}
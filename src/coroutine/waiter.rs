@@ -0,0 +1,31 @@
+use std::boxed::FnBox;
+
+use coroutine::channel::BlockedMessage;
+use error::CorosError;
+use Result;
+
+/// Hands a parked coroutine's BlockedMessage to whatever will wake it
+pub type ParkCallback = Box<FnBox(BlockedMessage) -> Result<()>>;
+
+/// Implemented by the channel receivers and locks that coroutines park on
+/// until there's something to take, see IoHandle::wait_for
+pub trait Waitable {
+    type Item;
+
+    /// Returns None while there's nothing to take yet
+    fn try_take(&mut self) -> Option<Result<Self::Item>>;
+
+    fn park_callback(&self) -> ParkCallback;
+
+    /// Called once a parked coroutine is running again, with whether it went
+    /// on to take something, so it can be removed from the parked waiters
+    fn finish_wait(&self, wait_id: usize, took: bool);
+}
+
+/// Adapts the result of a channel's try_recv for Waitable::try_take
+pub fn unless_empty<T>(result: Result<T>) -> Option<Result<T>> {
+    match result {
+        Err(CorosError::ChannelEmpty) => None,
+        result => Some(result),
+    }
+}
@@ -0,0 +1,183 @@
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+    unless_empty,
+};
+use error::CorosError;
+use Result;
+
+/// The latest value, and a version that's bumped every time it's replaced so
+/// receivers can tell whether they've seen it
+struct Watch<T: Send> {
+    is_sender_dropped: bool,
+    parked_receivers: Vec<BlockedMessage>,
+    value: T,
+    version: u64,
+}
+
+impl<T: Send> Watch<T> {
+    fn park_receiver(&mut self, blocked_message: BlockedMessage, seen_version: u64) -> Result<()> {
+        if seen_version != self.version || self.is_sender_dropped {
+            return blocked_message.wake()
+        }
+        self.parked_receivers.push(blocked_message);
+
+        Ok(())
+    }
+
+    fn take_parked_receivers(&mut self) -> Vec<BlockedMessage> {
+        mem::replace(&mut self.parked_receivers, Vec::new())
+    }
+}
+
+fn lock_watch<T: Send>(watch: &Mutex<Watch<T>>) -> MutexGuard<Watch<T>> {
+    watch
+        .lock()
+        .expect("Coros internal error: watch lock poisoned")
+}
+
+fn wake_all(parked_receivers: Vec<BlockedMessage>) {
+    for blocked_message in parked_receivers {
+        if let Err(err) = blocked_message.wake() {
+            error!("Error waking coroutine parked on watch: {:?}", err);
+        }
+    }
+}
+
+/// Replaces the watched value
+pub struct WatchSender<T: Send> {
+    watch: Arc<Mutex<Watch<T>>>,
+}
+
+impl<T: Send> WatchSender<T> {
+    /// Replaces the value without blocking, waking every parked receiver.
+    /// Receivers that haven't looked since only see the latest value.
+    pub fn send(&self, value: T) {
+        let parked_receivers = {
+            let mut watch = lock_watch(&self.watch);
+            watch.value = value;
+            watch.version += 1;
+
+            watch.take_parked_receivers()
+        };
+        wake_all(parked_receivers);
+    }
+
+    /// A new receiver, which has already seen the current value
+    pub fn subscribe(&self) -> WatchReceiver<T> {
+        WatchReceiver {
+            seen_version: lock_watch(&self.watch).version,
+            watch: self.watch.clone(),
+        }
+    }
+}
+
+/// Dropping the sender wakes every parked receiver with ChannelClosed
+impl<T: Send> Drop for WatchSender<T> {
+    fn drop(&mut self) {
+        let parked_receivers = match self.watch.lock() {
+            Ok(mut watch) => {
+                watch.is_sender_dropped = true;
+
+                watch.take_parked_receivers()
+            },
+            Err(_) => return,
+        };
+        wake_all(parked_receivers);
+    }
+}
+
+impl<T: Send> panic::RecoverSafe for WatchSender<T> {}
+impl<T: Send> panic::RefRecoverSafe for WatchSender<T> {}
+
+/// Waited on from inside a coroutine with IoHandle::changed. Receivers can be
+/// cloned, a cloned receiver has seen the same value as the one it was
+/// cloned from.
+#[derive(Clone)]
+pub struct WatchReceiver<T: Send> {
+    seen_version: u64,
+    watch: Arc<Mutex<Watch<T>>>,
+}
+
+impl<T: Clone + Send> WatchReceiver<T> {
+    /// The latest value, whether or not it's changed since it was last seen
+    pub fn get(&self) -> T {
+        lock_watch(&self.watch).value.clone()
+    }
+
+    /// Returns the latest value if it's changed since this receiver last
+    /// saw it, marking it seen. Otherwise returns ChannelEmpty, or
+    /// ChannelClosed once the sender has been dropped.
+    pub fn try_changed(&mut self) -> Result<T> {
+        let watch = lock_watch(&self.watch);
+        if watch.version != self.seen_version {
+            self.seen_version = watch.version;
+            let value = watch.value.clone();
+
+            return Ok(value)
+        }
+        if watch.is_sender_dropped {
+            return Err(CorosError::ChannelClosed)
+        }
+
+        Err(CorosError::ChannelEmpty)
+    }
+}
+
+impl<T: Clone + Send + 'static> Waitable for WatchReceiver<T> {
+    type Item = T;
+
+    fn try_take(&mut self) -> Option<Result<T>> {
+        unless_empty(self.try_changed())
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let watch = self.watch.clone();
+        let seen_version = self.seen_version;
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_watch(&watch).park_receiver(blocked_message, seen_version)
+        })
+    }
+
+    /// Every parked receiver is woken for each change, so there's no wakeup
+    /// to pass on
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        lock_watch(&self.watch)
+            .parked_receivers
+            .retain(|blocked_message| blocked_message.wait_id != wait_id);
+    }
+}
+
+impl<T: Send> panic::RecoverSafe for WatchReceiver<T> {}
+impl<T: Send> panic::RefRecoverSafe for WatchReceiver<T> {}
+
+/// A channel holding a single value that can be replaced, receivers are
+/// only interested in the latest value. The receiver starts out having seen
+/// the initial value.
+pub fn watch<T: Clone + Send>(initial: T) -> (WatchSender<T>, WatchReceiver<T>) {
+    let watch = Arc::new(Mutex::new(Watch {
+        is_sender_dropped: false,
+        parked_receivers: Vec::new(),
+        value: initial,
+        version: 0,
+    }));
+    let tx = WatchSender {
+        watch: watch.clone(),
+    };
+    let rx = WatchReceiver {
+        seen_version: 0,
+        watch: watch,
+    };
+
+    (tx, rx)
+}
@@ -1,8 +1,10 @@
+use std::any::Any;
 use std::error::{
     Error,
 };
 use std::fmt;
 use std::io::Error as IoError;
+use std::panic;
 use std::sync::{
     MutexGuard,
     PoisonError,
@@ -10,46 +12,87 @@ use std::sync::{
     RwLockWriteGuard,
     mpsc,
 };
+use std::thread;
 
 use context::error::ContextError;
 use deque::Stealer;
 use mio::{
     NotifyError,
-    Token,
     TimerError,
 };
-use scoped_threadpool::Pool as ThreadPool;
 
 use coroutine::Coroutine;
+use scheduler::SchedulerMessage;
 
 #[derive(Debug)]
 pub enum CorosError {
     CannotStartPoolWithoutSchedulers,
+    ChannelClosed,
+    ChannelEmpty,
+    ChannelFull,
     CoroutineAlreadyJoined,
-    CoroutineBlockedOnIoAwokenForNotIo,
-    CoroutineBlockSendError,
-    CoroutineChannelSendError,
-    CoroutinePanic,
+    CoroutineCancelled,
+    CoroutinePanic(CoroutinePanicDetails),
     InvalidCoroutineContext(ContextError),
     InvalidCoroutineNoCallback,
-    InvalidCoroutineSlabContents,
     InvalidPoolNoSchedulerResultReceiver,
     InvalidThreadForSpawn(u32, u32),
+    IoOwnerShutDown,
+    Lagged(usize),
     MioIoError(IoError),
     MioTimerError(TimerError),
-    MioNotifyError(NotifyError<Token>),
+    MioNotifyError(NotifyError<SchedulerMessage>),
     MissingCoroutine,
     RecvError(mpsc::RecvError),
-    SendIoResultToCoroutineError,
     SlabFull,
     ThreadPoolReadLockPoisoned,
     ThreadPoolWriteLockPoisoned,
+    ThreadPanic,
+    TimedOut,
     TriedToSpawnCoroutineOnShutdownThread,
     TryRecvError(mpsc::TryRecvError),
+    UnableToMapStack(IoError),
+    UnableToProtectStackGuardPage(IoError),
     UnableToReceiveThreadShutdownResult(mpsc::RecvError),
     UnableToSendThreadShutdownSignal,
+    UnableToSpawnThread(IoError),
     UncleanShutdown(Vec<CorosError>),
+    WorkSenderMutexPoisoned,
     WorkStealerMutexPoisoned,
+    ZeroCapacityChannel,
+}
+
+/// What a coroutine body panicked with, and which coroutine it was. The
+/// payload is whatever was passed to panic!, so it can be resumed unchanged.
+pub struct CoroutinePanicDetails {
+    pub coroutine_id: usize,
+    pub payload: Box<Any + Send>,
+}
+
+impl CoroutinePanicDetails {
+    /// The panic message, if the body panicked with one. Panics with
+    /// formatted messages carry a String, panics with literals a &str.
+    pub fn message(&self) -> Option<&str> {
+        if let Some(message) = self.payload.downcast_ref::<&'static str>() {
+            return Some(*message)
+        }
+
+        self.payload.downcast_ref::<String>().map(|message| &message[..])
+    }
+
+    /// Continues the coroutine's panic on the calling thread
+    pub fn resume(self) -> ! {
+        panic::propagate(self.payload)
+    }
+}
+
+impl fmt::Debug for CoroutinePanicDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CoroutinePanicDetails")
+            .field("coroutine_id", &self.coroutine_id)
+            .field("message", &self.message())
+            .finish()
+    }
 }
 
 impl CorosError {
@@ -58,34 +101,40 @@ impl CorosError {
             CorosError::CannotStartPoolWithoutSchedulers => {
                 "Cannot start pool without schedulers"
             },
+            CorosError::ChannelClosed => {
+                "Channel closed, all of its senders or receivers were dropped"
+            },
+            CorosError::ChannelEmpty => {
+                "No message waiting on the channel"
+            },
+            CorosError::ChannelFull => {
+                "Cannot send message to a full bounded channel"
+            },
             CorosError::CoroutineAlreadyJoined => {
                 "Coroutine already joined"
             }
-            CorosError::CoroutineBlockedOnIoAwokenForNotIo => {
-                "Coroutine was blocked on IO but awoken for not IO"
-            }
-            CorosError::CoroutineBlockSendError => {
-                "Cannot send message to block coroutine"
+            CorosError::CoroutineCancelled => {
+                "Coroutine was cancelled before it finished"
             },
-            CorosError::CoroutineChannelSendError => {
-                "Cannot send message via channel to a finshed coroutine"
-            },
-            CorosError::CoroutinePanic => {
+            CorosError::CoroutinePanic(_) => {
                 "Panic while executing coroutine body"
             },
             CorosError::InvalidCoroutineContext(ref err) => err.description(),
             CorosError::InvalidCoroutineNoCallback => {
                 "Coroutine in invalid state, has no execution callback"
             },
-            CorosError::InvalidCoroutineSlabContents => {
-                "Invalid coroutine slab contents"
-            }
             CorosError::InvalidPoolNoSchedulerResultReceiver => {
                 "Invalid coroutine pool, no native thread result receiver"
             },
             CorosError::InvalidThreadForSpawn(_, _) => {
                 "Index of thread for coroutine spawn greater then thread count"
             },
+            CorosError::IoOwnerShutDown => {
+                "The scheduler the IO is registered with has shut down"
+            },
+            CorosError::Lagged(_) => {
+                "Broadcast receiver fell behind and missed messages"
+            },
             CorosError::MioIoError(ref err) => err.description(),
             CorosError::MioTimerError(ref err) => err.description(),
             CorosError::MioNotifyError(ref err) => err.description(),
@@ -93,9 +142,6 @@ impl CorosError {
                 "Attempting to fetch missing coroutine from suspension"
             }
             CorosError::RecvError(ref err) => err.description(),
-            CorosError::SendIoResultToCoroutineError => {
-                "Error sending IO result to coroutine"
-            },
             CorosError::SlabFull => {
                 "Error attempting to insert a suspended coroutine into a full slab"
             }
@@ -105,20 +151,35 @@ impl CorosError {
             CorosError::ThreadPoolWriteLockPoisoned => {
                 "Pool's thread pool write lock poisoned"
             },
+            CorosError::ThreadPanic => {
+                "Native thread panicked outside of a coroutine"
+            },
+            CorosError::TimedOut => {
+                "Timed out waiting for a blocking operation"
+            },
             CorosError::TriedToSpawnCoroutineOnShutdownThread => {
                 "Pool tried to spawn coroutine onto a native thread that is shutdown"
             },
             CorosError::TryRecvError(ref err) => err.description(),
+            CorosError::UnableToMapStack(ref err) => err.description(),
+            CorosError::UnableToProtectStackGuardPage(ref err) => err.description(),
             CorosError::UnableToReceiveThreadShutdownResult(ref err) => err.description(),
             CorosError::UnableToSendThreadShutdownSignal => {
                 "Error sending shutdown message to native thread"
             },
+            CorosError::UnableToSpawnThread(ref err) => err.description(),
             CorosError::UncleanShutdown(_) => {
                 "Unable to shutdown all native threads"
             }
+            CorosError::WorkSenderMutexPoisoned => {
+                "Pool work sender mutex poisoned"
+            },
             CorosError::WorkStealerMutexPoisoned => {
                 "Thread scheduler work stealer mutex poisoned"
             },
+            CorosError::ZeroCapacityChannel => {
+                "Bounded channels need a capacity of at least one"
+            },
         }
     }
 }
@@ -131,31 +192,39 @@ impl Error for CorosError {
     fn cause(&self) -> Option<&Error> {
         match *self {
             CorosError::CannotStartPoolWithoutSchedulers => None,
+            CorosError::ChannelClosed => None,
+            CorosError::ChannelEmpty => None,
+            CorosError::ChannelFull => None,
             CorosError::CoroutineAlreadyJoined => None,
-            CorosError::CoroutineBlockedOnIoAwokenForNotIo => None,
-            CorosError::CoroutineBlockSendError => None,
-            CorosError::CoroutineChannelSendError => None,
-            CorosError::CoroutinePanic => None,
+            CorosError::CoroutineCancelled => None,
+            CorosError::CoroutinePanic(_) => None,
             CorosError::InvalidCoroutineContext(ref err) => Some(err),
             CorosError::InvalidCoroutineNoCallback => None,
-            CorosError::InvalidCoroutineSlabContents => None,
             CorosError::InvalidPoolNoSchedulerResultReceiver => None,
             CorosError::InvalidThreadForSpawn(_, _) => None,
+            CorosError::IoOwnerShutDown => None,
+            CorosError::Lagged(_) => None,
             CorosError::MioIoError(ref err) => Some(err),
             CorosError::MioTimerError(ref err) => Some(err),
             CorosError::MioNotifyError(ref err) => Some(err),
             CorosError::MissingCoroutine => None,
             CorosError::RecvError(ref err) => Some(err),
-            CorosError::SendIoResultToCoroutineError => None,
             CorosError::SlabFull => None,
             CorosError::ThreadPoolReadLockPoisoned => None,
             CorosError::ThreadPoolWriteLockPoisoned => None,
+            CorosError::ThreadPanic => None,
+            CorosError::TimedOut => None,
             CorosError::TriedToSpawnCoroutineOnShutdownThread => None,
             CorosError::TryRecvError(ref err) => Some(err),
+            CorosError::UnableToMapStack(ref err) => Some(err),
+            CorosError::UnableToProtectStackGuardPage(ref err) => Some(err),
             CorosError::UnableToReceiveThreadShutdownResult(ref err) => Some(err),
             CorosError::UnableToSendThreadShutdownSignal => None,
+            CorosError::UnableToSpawnThread(ref err) => Some(err),
             CorosError::UncleanShutdown(_) => None,
+            CorosError::WorkSenderMutexPoisoned => None,
             CorosError::WorkStealerMutexPoisoned => None,
+            CorosError::ZeroCapacityChannel => None,
         }
     }
 }
@@ -184,34 +253,41 @@ impl From<TimerError> for CorosError {
     }
 }
 
-impl From<NotifyError<Token>> for CorosError {
-    fn from(err: NotifyError<Token>) -> CorosError {
+impl From<NotifyError<SchedulerMessage>> for CorosError {
+    fn from(err: NotifyError<SchedulerMessage>) -> CorosError {
         error!("Error notifying coroutine");
         CorosError::MioNotifyError(err)
     }
 }
 
-impl<'a> From<PoisonError<RwLockReadGuard<'a, Option<ThreadPool>>>> for CorosError {
-    fn from(err: PoisonError<RwLockReadGuard<'a, Option<ThreadPool>>>) -> CorosError {
+impl<'a> From<PoisonError<RwLockReadGuard<'a, Option<Vec<thread::JoinHandle<()>>>>>> for CorosError {
+    fn from(err: PoisonError<RwLockReadGuard<'a, Option<Vec<thread::JoinHandle<()>>>>>) -> CorosError {
         error!("Error obtaining thread pool read lock {:?}", err);
         CorosError::ThreadPoolReadLockPoisoned
     }
 }
 
-impl<'a> From<PoisonError<RwLockWriteGuard<'a, Option<ThreadPool>>>> for CorosError {
-    fn from(err: PoisonError<RwLockWriteGuard<'a, Option<ThreadPool>>>) -> CorosError {
+impl<'a> From<PoisonError<RwLockWriteGuard<'a, Option<Vec<thread::JoinHandle<()>>>>>> for CorosError {
+    fn from(err: PoisonError<RwLockWriteGuard<'a, Option<Vec<thread::JoinHandle<()>>>>>) -> CorosError {
         error!("Error obtaining thread pool write lock {:?}", err);
         CorosError::ThreadPoolWriteLockPoisoned
     }
 }
 
-impl<'a> From<PoisonError<MutexGuard<'a, Vec<Stealer<Coroutine>>>>> for CorosError {
-    fn from(err: PoisonError<MutexGuard<'a, Vec<Stealer<Coroutine>>>>) -> CorosError {
+impl<'a> From<PoisonError<MutexGuard<'a, Vec<Stealer<Box<Coroutine>>>>>> for CorosError {
+    fn from(err: PoisonError<MutexGuard<'a, Vec<Stealer<Box<Coroutine>>>>>) -> CorosError {
         error!("Error obtaining thread scheduler work stealer lock {:?}", err);
         CorosError::WorkStealerMutexPoisoned
     }
 }
 
+impl<'a> From<PoisonError<MutexGuard<'a, Vec<mpsc::Sender<Box<Coroutine>>>>>> for CorosError {
+    fn from(err: PoisonError<MutexGuard<'a, Vec<mpsc::Sender<Box<Coroutine>>>>>) -> CorosError {
+        error!("Error obtaining pool work sender lock {:?}", err);
+        CorosError::WorkSenderMutexPoisoned
+    }
+}
+
 impl From<mpsc::RecvError> for CorosError {
     fn from(err: mpsc::RecvError) -> CorosError {
         CorosError::RecvError(err)
@@ -0,0 +1,193 @@
+use std::any::Any;
+use std::boxed::FnBox;
+use std::panic;
+use std::result;
+
+use context::Context;
+
+use stack::CoroutineStack;
+use stack_cache::size_class;
+use Result;
+
+/// Panic payload used to unwind the stack of a generator dropped before it
+/// finished
+struct GeneratorCancellation;
+
+/// Runs the generator's closure, then hands back the generator's context and
+/// its caller's for generator_init to switch between. The body returns
+/// rather than switching back itself, so that it's freed, along with
+/// everything the closure captured, before its stack is left for good.
+type GeneratorBody = Box<FnBox() -> (*const Context, *const Context)>;
+
+/// Shared between a generator and the body running on its stack. Boxed so
+/// the body can keep pointing at it while the generator is moved around.
+struct GeneratorState<Y, R> {
+    body: Option<GeneratorBody>,
+    caller_context: Context,
+    is_cancelled: bool,
+    is_finished: bool,
+    result: Option<result::Result<R, Box<Any + Send>>>,
+    stack: Option<CoroutineStack>,
+    yielded: Option<Y>,
+}
+
+impl<Y, R> GeneratorState<Y, R> {
+    fn context(&self) -> &Context {
+        &self.stack
+            .as_ref()
+            .expect("Coros internal error: generator has no stack")
+            .context
+    }
+}
+
+extern "C" fn generator_init(body_ptr: usize, _: usize) -> ! {
+    let maybe_body = unsafe { &mut *(body_ptr as *mut Option<GeneratorBody>) };
+    let body = maybe_body
+        .take()
+        .expect("Coros internal error: cannot run generator without body");
+
+    let (generator_context, caller_context) = body.call_box(());
+    unsafe { Context::swap(&*generator_context, &*caller_context) };
+
+    unreachable!("Coros internal error: finished generators are never resumed");
+}
+
+/// Runs a closure on its own stack, switching back to the caller each time
+/// the closure yields a value. It's resumed by iterating over it, the
+/// closure's return value can be taken once it's finished. Generators run
+/// on whichever thread or coroutine iterates them, they don't need a pool.
+/// Their stacks have guard pages, but unlike a coroutine's an overflow
+/// isn't reported, it's left to crash the process as a plain segfault.
+pub struct Generator<Y, R> {
+    state: Box<GeneratorState<Y, R>>,
+}
+
+impl<Y: 'static, R: 'static> Generator<Y, R> {
+    /// The closure only borrows its handle, so the handle can't escape the
+    /// closure and be used after the generator is gone
+    pub fn new<F>(generator_body: F, stack_size: usize) -> Result<Generator<Y, R>>
+        where F: for<'h> FnOnce(&'h mut GeneratorHandle<Y, R>) -> R + panic::RecoverSafe + 'static
+    {
+        let mut state = Box::new(GeneratorState {
+            body: None,
+            caller_context: Context::empty(),
+            is_cancelled: false,
+            is_finished: false,
+            result: None,
+            stack: None,
+            yielded: None,
+        });
+        let state_ptr: *mut GeneratorState<Y, R> = &mut *state;
+
+        state.body = Some(Box::new(move || {
+            let mut generator_handle = GeneratorHandle {
+                state: state_ptr,
+            };
+            let result = panic::recover(move || {
+                generator_body(&mut generator_handle)
+            });
+
+            let state = unsafe { &mut *state_ptr };
+            state.is_finished = true;
+            match result {
+                Err(ref payload) if payload.is::<GeneratorCancellation>() => (),
+                result => state.result = Some(result),
+            }
+
+            (state.context() as *const Context, &state.caller_context as *const Context)
+        }));
+        let body_ptr = &mut state.body as *mut Option<GeneratorBody> as usize;
+        state.stack = Some(try!(CoroutineStack::new(
+            generator_init,
+            body_ptr,
+            size_class(stack_size),
+            false,
+        )));
+
+        Ok(Generator {
+            state: state,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished
+    }
+
+    /// The closure's return value, once the generator has finished. It can
+    /// only be taken once.
+    pub fn take_result(&mut self) -> Option<R> {
+        match self.state.result.take() {
+            Some(Ok(result)) => Some(result),
+            _ => None,
+        }
+    }
+
+    fn resume(&mut self) {
+        Context::swap(&self.state.caller_context, self.state.context());
+    }
+}
+
+impl<Y, R> panic::RecoverSafe for Generator<Y, R> {}
+impl<Y, R> panic::RefRecoverSafe for Generator<Y, R> {}
+
+/// Yields the next value, or returns None once the closure has finished. If
+/// the closure panicked its panic is continued on the caller.
+impl<Y: 'static, R: 'static> Iterator for Generator<Y, R> {
+    type Item = Y;
+
+    fn next(&mut self) -> Option<Y> {
+        if self.state.is_finished {
+            return None
+        }
+        self.resume();
+
+        if let Some(value) = self.state.yielded.take() {
+            return Some(value)
+        }
+        if let Some(Err(payload)) = self.state.result.take() {
+            panic::propagate(payload);
+        }
+
+        None
+    }
+}
+
+/// A generator dropped part way through is resumed one last time to unwind
+/// its stack, so values owned by the closure are dropped
+impl<Y, R> Drop for Generator<Y, R> {
+    fn drop(&mut self) {
+        let is_suspended = self.state.body.is_none() && !self.state.is_finished;
+        if !is_suspended {
+            return
+        }
+
+        self.state.is_cancelled = true;
+        Context::swap(&self.state.caller_context, self.state.context());
+        if let Some(Err(_)) = self.state.result {
+            error!("Generator body panicked while it was unwound");
+        }
+    }
+}
+
+/// Lent to a generator's closure to yield values back to its caller
+pub struct GeneratorHandle<Y, R> {
+    state: *mut GeneratorState<Y, R>,
+}
+
+impl<Y, R> GeneratorHandle<Y, R> {
+    /// Passes the value to the caller and suspends the closure until the
+    /// generator is next resumed
+    pub fn yield_value(&mut self, value: Y) {
+        let state = unsafe { &mut *self.state };
+        state.yielded = Some(value);
+
+        Context::swap(state.context(), &state.caller_context);
+
+        if state.is_cancelled {
+            panic::propagate(Box::new(GeneratorCancellation));
+        }
+    }
+}
+
+impl<Y, R> panic::RecoverSafe for GeneratorHandle<Y, R> {}
+impl<Y, R> panic::RefRecoverSafe for GeneratorHandle<Y, R> {}
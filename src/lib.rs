@@ -2,25 +2,51 @@
 
 extern crate context;
 extern crate deque;
+extern crate libc;
 #[macro_use] extern crate log;
 extern crate mio;
+extern crate num_cpus;
 extern crate rand;
-extern crate scoped_threadpool;
 extern crate slab;
+extern crate time;
 
 mod coroutine;
 pub use coroutine::io_handle::IoHandle;
 pub use coroutine::join_handle::JoinHandle;
+pub use coroutine::registration::Registration;
+pub use coroutine::select::{
+    Selected,
+    WaitSpec,
+};
 mod error;
-pub use error::CorosError;
+pub use error::{
+    CoroutinePanicDetails,
+    CorosError,
+};
 pub use coroutine::channel::{
     self,
     Receiver,
     Sender,
 };
 mod scheduler;
+mod generator;
+pub use generator::{
+    Generator,
+    GeneratorHandle,
+};
 mod pool;
-pub use pool::Pool;
+pub use pool::{
+    Pool,
+    PoolBuilder,
+};
+mod scope;
+pub use scope::Scope;
+mod spawner;
+pub use spawner::Spawner;
+mod stack;
+pub use stack::StackUsageHistogram;
+mod stack_cache;
+pub mod sync;
 
 use std::result;
 pub type Result<T> = result::Result<T, CorosError>;
@@ -1,27 +1,22 @@
-use std::{fmt, panic};
+use std::{fmt, panic, thread};
 use std::sync::{
+    Arc,
     Mutex,
     RwLock,
 };
 use std::sync::mpsc::{
     channel,
     Receiver,
+    RecvError,
     Sender,
 };
 
-use context::stack::{
-    Stack,
-};
 use deque::{
     self,
     Stealer,
     Worker,
 };
-use rand::{
-    Rng,
-    thread_rng,
-};
-use scoped_threadpool::Pool as ThreadPool;
+use num_cpus;
 
 use Result;
 use coroutine::{
@@ -31,20 +26,157 @@ use coroutine::io_handle::IoHandle;
 use coroutine::join_handle::JoinHandle;
 use error::CorosError;
 use scheduler::Scheduler;
+use scope::{
+    Scope,
+    join_scope,
+    new_scope,
+};
+use spawner::Spawner;
+use stack::StackUsageHistogram;
+use stack_cache::StackCacheLimits;
+
+pub const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+pub const DEFAULT_BLOCKED_COROUTINE_CAPACITY: usize = 1024 * 64;
+
+pub type ThreadHook = Arc<Fn() + Send + Sync + 'static>;
 
 struct SchedulerHandle {
     shutdown_tx: Sender<()>,
     scheduler: Mutex<Option<Scheduler>>,
-    work_tx: Sender<Coroutine>,
+}
+
+pub struct PoolBuilder {
+    name: String,
+    thread_count: Option<u32>,
+    stack_size: usize,
+    blocked_coroutine_capacity: usize,
+    measure_stack_usage: bool,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>,
+    stack_cache_limits: StackCacheLimits,
+}
+
+impl PoolBuilder {
+    pub fn new() -> PoolBuilder {
+        PoolBuilder {
+            name: "coros".to_string(),
+            thread_count: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            blocked_coroutine_capacity: DEFAULT_BLOCKED_COROUTINE_CAPACITY,
+            measure_stack_usage: false,
+            on_thread_start: None,
+            on_thread_stop: None,
+            stack_cache_limits: StackCacheLimits::new(),
+        }
+    }
+
+    /// Scheduler threads are named after the pool, suffixed with their index
+    pub fn name(mut self, name: String) -> PoolBuilder {
+        self.name = name;
+        self
+    }
+
+    /// Defaults to the number of CPUs
+    pub fn thread_count(mut self, thread_count: u32) -> PoolBuilder {
+        self.thread_count = Some(thread_count);
+        self
+    }
+
+    /// The stack size used by spawn_with_default_stack
+    pub fn stack_size(mut self, stack_size: usize) -> PoolBuilder {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// How many coroutines each scheduler can have blocked at once
+    pub fn blocked_coroutine_capacity(mut self, blocked_coroutine_capacity: usize) -> PoolBuilder {
+        self.blocked_coroutine_capacity = blocked_coroutine_capacity;
+        self
+    }
+
+    /// Paints each new coroutine stack so that how much of it was used can be
+    /// measured when the coroutine finishes, see
+    /// JoinHandle::stack_high_water_mark and Pool::stack_usage_histogram.
+    /// Stacks aren't cached while measuring.
+    pub fn measure_stack_usage(mut self, measure_stack_usage: bool) -> PoolBuilder {
+        self.measure_stack_usage = measure_stack_usage;
+        self
+    }
+
+    /// How many finished coroutines' stacks each scheduler keeps for reuse,
+    /// per power of two size class. Zero turns stack caching off.
+    pub fn max_cached_stacks_per_size_class(mut self, max_cached_stacks: usize) -> PoolBuilder {
+        self.stack_cache_limits.max_stacks_per_size_class = max_cached_stacks;
+        self
+    }
+
+    /// The most stack memory, in bytes, each scheduler keeps cached across
+    /// all size classes
+    pub fn max_cached_stack_bytes(mut self, max_cached_stack_bytes: usize) -> PoolBuilder {
+        self.stack_cache_limits.max_bytes = max_cached_stack_bytes;
+        self
+    }
+
+    /// Run on each scheduler thread before it starts running coroutines
+    pub fn on_thread_start<F>(mut self, on_thread_start: F) -> PoolBuilder
+        where F: Fn() + Send + Sync + 'static
+    {
+        self.on_thread_start = Some(Arc::new(on_thread_start));
+        self
+    }
+
+    /// Run on each scheduler thread after its scheduler shuts down
+    pub fn on_thread_stop<F>(mut self, on_thread_stop: F) -> PoolBuilder
+        where F: Fn() + Send + Sync + 'static
+    {
+        self.on_thread_stop = Some(Arc::new(on_thread_stop));
+        self
+    }
+
+    pub fn build(self) -> Result<Pool> {
+        let thread_count = match self.thread_count {
+            Some(thread_count) => thread_count,
+            None => num_cpus::get() as u32,
+        };
+        let mut pool = Pool {
+            is_running: false,
+            name: self.name,
+            blocked_coroutine_capacity: self.blocked_coroutine_capacity,
+            on_thread_start: self.on_thread_start,
+            on_thread_stop: self.on_thread_stop,
+            stack_cache_limits: self.stack_cache_limits,
+            stack_size: self.stack_size,
+            stack_usage: if self.measure_stack_usage {
+                Some(Arc::new(Mutex::new(StackUsageHistogram::new())))
+            } else {
+                None
+            },
+            thread_count: thread_count,
+            thread_pool: RwLock::new(None),
+            scheduler_result_rx: None,
+            scheduler_handles: None,
+            work_txs: Arc::new(Mutex::new(Vec::new())),
+        };
+        try!(pool.create_scheduler_handles());
+
+        Ok(pool)
+    }
 }
 
 pub struct Pool {
     pub is_running: bool,
     pub name: String,
+    blocked_coroutine_capacity: usize,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>,
+    stack_cache_limits: StackCacheLimits,
+    stack_size: usize,
+    stack_usage: Option<Arc<Mutex<StackUsageHistogram>>>,
     thread_count: u32,
-    thread_pool: RwLock<Option<ThreadPool>>,
+    thread_pool: RwLock<Option<Vec<thread::JoinHandle<()>>>>,
     scheduler_result_rx: Option<Receiver<Result<()>>>,
     scheduler_handles: Option<Vec<SchedulerHandle>>,
+    work_txs: Arc<Mutex<Vec<Sender<Box<Coroutine>>>>>,
 }
 
 impl fmt::Display for Pool {
@@ -61,24 +193,18 @@ impl fmt::Debug for Pool {
 
 impl Pool {
     pub fn new(name: String, thread_count: u32) -> Result<Pool> {
-        let mut pool = Pool {
-            is_running: false,
-            name: name,
-            thread_count: thread_count,
-            thread_pool: RwLock::new(None),
-            scheduler_result_rx: None,
-            scheduler_handles: None,
-        };
-        try!(pool.create_scheduler_handles());
-
-        Ok(pool)
+        PoolBuilder::new()
+            .name(name)
+            .thread_count(thread_count)
+            .build()
     }
 
     pub fn create_scheduler_handles(&mut self) -> Result<()> {
         let thread_count: usize = self.thread_count as usize;
         let mut scheduler_handles: Vec<SchedulerHandle> = Vec::with_capacity(thread_count);
-        let mut work_stealers: Vec<Stealer<Coroutine>> = Vec::with_capacity(thread_count);
-        let mut work_providers: Vec<Worker<Coroutine>> = Vec::with_capacity(thread_count);
+        let mut work_stealers: Vec<Stealer<Box<Coroutine>>> = Vec::with_capacity(thread_count);
+        let mut work_providers: Vec<Worker<Box<Coroutine>>> = Vec::with_capacity(thread_count);
+        let mut work_txs: Vec<Sender<Box<Coroutine>>> = Vec::with_capacity(thread_count);
         let (result_tx, result_rx) = channel();
 
         for _ in 0..thread_count {
@@ -91,8 +217,13 @@ impl Pool {
             let (work_tx, work_rx) = channel();
 
             let scheduler = try!(Scheduler::new(
+                self.blocked_coroutine_capacity,
+                self.stack_size,
+                self.name.clone(),
                 result_tx.clone(),
                 shutdown_rx,
+                self.stack_cache_limits,
+                self.stack_usage.clone(),
                 work_provider,
                 work_rx,
                 work_stealers.clone(),
@@ -100,19 +231,55 @@ impl Pool {
             let scheduler_handle = SchedulerHandle {
                 shutdown_tx: shutdown_tx,
                 scheduler: Mutex::new(Some(scheduler)),
-                work_tx: work_tx,
             };
             scheduler_handles.push(scheduler_handle);
+            work_txs.push(work_tx);
         }
 
         self.scheduler_result_rx = Some(result_rx);
         self.scheduler_handles = Some(scheduler_handles);
+        *try!(self.work_txs.lock()) = work_txs;
 
         Ok(())
     }
 
+    /// How deep the stacks of the pool's finished coroutines got, if it was
+    /// built with measure_stack_usage
+    pub fn stack_usage_histogram(&self) -> Option<StackUsageHistogram> {
+        self.stack_usage.as_ref().map(|stack_usage| {
+            stack_usage
+                .lock()
+                .expect("Coros internal error: stack usage lock poisoned")
+                .clone()
+        })
+    }
+
+    /// Runs the closure with a scope for spawning coroutines that borrow from
+    /// the caller's stack. Every coroutine spawned in the scope is joined
+    /// before this returns, and if any of them panicked the first panic is
+    /// continued here. The pool must be running, and this must be called
+    /// from a thread rather than one of the pool's coroutines, since joining
+    /// blocks the thread.
+    pub fn scope<'pool, 'scope, F, R>(&'pool self, scope_body: F) -> R
+        where F: FnOnce(&Scope<'pool, 'scope>) -> R
+    {
+        let scope = new_scope(self.spawner());
+        let result = scope_body(&scope);
+        if let Some(details) = join_scope(&scope) {
+            details.resume();
+        }
+
+        result
+    }
+
+    /// A cloneable handle for spawning coroutines onto this pool from other
+    /// threads, which stays valid across pool stops and starts
+    pub fn spawner(&self) -> Spawner {
+        Spawner::new(self.work_txs.clone(), self.stack_size)
+    }
+
     pub fn spawn_with_thread_index<F, T>(
-        &mut self,
+        &self,
         coroutine_body: F,
         stack_size: usize,
         thread_index: u32
@@ -120,126 +287,144 @@ impl Pool {
         where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
               T: Send + 'static,
     {
-        let scheduler_handles = match self.scheduler_handles {
-            Some(ref scheduler_handles) => scheduler_handles,
-            None => return Err(CorosError::CannotStartPoolWithoutSchedulers),
-        };
-
-        let scheduler_handle = match scheduler_handles.get(thread_index as usize) {
-            None => {
-                return Err(CorosError::InvalidThreadForSpawn(thread_index, self.thread_count))
-            }
-            Some(scheduler_handle) => scheduler_handle,
-        };
-
-        let (coroutine_result_tx, coroutine_result_rx) = channel();
-        let coroutine_function = Box::new(move |coroutine_handle: IoHandle| {
-            let maybe_coroutine_result = panic::recover(move || {
-                coroutine_body(coroutine_handle)
-            });
-
-            let result = match maybe_coroutine_result {
-                Ok(coroutine_result) => Ok(coroutine_result),
-                Err(err) => {
-                    error!("Coroutine body panicked with: {:?}", err);
-                    Err(CorosError::CoroutinePanic)
-                },
-            };
-            coroutine_result_tx
-                .send(result)
-                .expect("Coros internal error: attempting to send coroutine result to closed channel");
-        });
-
-        let coroutine = Coroutine::new(
-            coroutine_function,
-            Stack::new(stack_size),
-        );
-
-        if let Err(_) = scheduler_handle.work_tx.send(coroutine) {
-          return Err(CorosError::TriedToSpawnCoroutineOnShutdownThread)
-        }
-
-        Ok(JoinHandle::<T>::new(coroutine_result_rx))
+        self.spawner().spawn_with_thread_index(coroutine_body, stack_size, thread_index)
     }
 
-    pub fn spawn<F, T>(&mut self, coroutine_body: F, stack_size: usize) -> Result<JoinHandle<T>>
+    pub fn spawn<F, T>(&self, coroutine_body: F, stack_size: usize) -> Result<JoinHandle<T>>
         where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
               T: Send + 'static,
     {
-        let thread_count = self.thread_count;
-        self.spawn_with_thread_index(
-            coroutine_body,
-            stack_size,
-            thread_rng().gen_range(0, thread_count) as u32,
-        )
+        self.spawner().spawn(coroutine_body, stack_size)
     }
 
+    pub fn spawn_with_default_stack<F, T>(&self, coroutine_body: F) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        self.spawner().spawn_with_default_stack(coroutine_body)
+    }
 
     pub fn start(&mut self) -> Result<()> {
         if self.is_running {
             return Ok(())
         }
-        self.is_running = true;
 
-        let mut maybe_thread_pool =  try!(self.thread_pool.write());
-        let mut thread_pool = ThreadPool::new(self.thread_count);
+        match self.spawn_scheduler_threads() {
+            Ok(thread_pool) => {
+                *try!(self.thread_pool.write()) = Some(thread_pool);
+                self.is_running = true;
+
+                Ok(())
+            },
+            Err(err) => {
+                // Schedulers are moved onto their threads, so the ones that
+                // were started need replacing before the pool can start again
+                try!(self.create_scheduler_handles());
+
+                Err(err)
+            },
+        }
+    }
+
+    /// Runs each scheduler on its own thread. If a thread can't be spawned
+    /// the threads already running are shut down again, so none are left
+    /// running without a handle to stop them.
+    fn spawn_scheduler_threads(&self) -> Result<Vec<thread::JoinHandle<()>>> {
         let scheduler_handles = match self.scheduler_handles {
             Some(ref scheduler_handles) => scheduler_handles,
             None => return Err(CorosError::CannotStartPoolWithoutSchedulers),
         };
-        thread_pool.scoped(|scoped| {
-            for scheduler_handle in scheduler_handles.iter() {
-                let mut scheduler = scheduler_handle.scheduler
-                    .lock()
-                    .expect("Coros internal error: scheduler lock poisoned");
-                match scheduler.take() {
-                    Some(mut scheduler) => {
-                        scoped.execute(move || { scheduler.run() })
-                    },
-                    None => panic!("Coros internal error: starting coroutine pool without full set of schedulers"),
+        let mut thread_pool = Vec::with_capacity(self.thread_count as usize);
+        for (thread_index, scheduler_handle) in scheduler_handles.iter().enumerate() {
+            let mut scheduler = match scheduler_handle.scheduler
+                .lock()
+                .expect("Coros internal error: scheduler lock poisoned")
+                .take() {
+                Some(scheduler) => scheduler,
+                None => panic!("Coros internal error: starting coroutine pool without full set of schedulers"),
+            };
+            let on_thread_start = self.on_thread_start.clone();
+            let on_thread_stop = self.on_thread_stop.clone();
+
+            let thread_builder = thread::Builder::new()
+                .name(format!("{}-{}", self.name, thread_index));
+            let spawn_result = thread_builder.spawn(move || {
+                if let Some(on_thread_start) = on_thread_start {
+                    on_thread_start();
                 }
+                scheduler.run();
+                if let Some(on_thread_stop) = on_thread_stop {
+                    on_thread_stop();
+                }
+            });
+            match spawn_result {
+                Ok(thread) => thread_pool.push(thread),
+                Err(err) => {
+                    match self.shut_down_threads(thread_pool) {
+                        Ok(ref errors) if errors.is_empty() => (),
+                        Ok(errors) => error!("Error shutting down threads after failed start: {:?}", errors),
+                        Err(shutdown_err) => error!("Error shutting down threads after failed start: {:?}", shutdown_err),
+                    }
+
+                    return Err(CorosError::UnableToSpawnThread(err))
+                },
             }
-        });
-        *maybe_thread_pool = Some(thread_pool);
-        self.is_running = true;
+        }
 
-        Ok(())
+        Ok(thread_pool)
+    }
+
+    /// Signals the schedulers running on the given threads to shut down,
+    /// which are the first ones, then joins the threads and collects the
+    /// schedulers' results. Schedulers that haven't started still hold
+    /// result senders, so results are only taken for threads that exited
+    /// cleanly, which have already sent them.
+    fn shut_down_threads(&self, thread_pool: Vec<thread::JoinHandle<()>>) -> Result<Vec<CorosError>> {
+        let mut errors = Vec::with_capacity(thread_pool.len());
+        let thread_count = thread_pool.len();
+
+        let scheduler_handles = match self.scheduler_handles {
+            Some(ref scheduler_handles) => scheduler_handles,
+            None => return Err(CorosError::CannotStartPoolWithoutSchedulers),
+        };
+        for scheduler_handle in scheduler_handles.iter().take(thread_count) {
+            if let Err(_) = scheduler_handle.shutdown_tx.send(()) {
+                errors.push(CorosError::UnableToSendThreadShutdownSignal);
+            }
+        }
+        let mut exited_thread_count = 0;
+        for thread in thread_pool {
+            match thread.join() {
+                Ok(_) => exited_thread_count += 1,
+                Err(_) => errors.push(CorosError::ThreadPanic),
+            }
+        }
+
+        let scheduler_result_rx = match self.scheduler_result_rx {
+            Some(ref scheduler_result_rx) => scheduler_result_rx,
+            None => return Err(CorosError::InvalidPoolNoSchedulerResultReceiver),
+        };
+        for _ in 0..exited_thread_count {
+            if let Err(_) = scheduler_result_rx.try_recv() {
+                errors.push(CorosError::UnableToReceiveThreadShutdownResult(RecvError));
+            }
+        }
+
+        Ok(errors)
     }
 
     pub fn stop(&mut self) -> Result<()> {
         if !self.is_running {
             return Ok(());
         }
-        let mut errors = Vec::with_capacity(self.thread_count as usize);
-
-        {
+        let thread_pool = {
             let mut maybe_thread_pool =  try!(self.thread_pool.write());
-            let mut thread_pool = match maybe_thread_pool.take() {
+            match maybe_thread_pool.take() {
                 Some(thread_pool) => thread_pool,
                 None => panic!("Coros internal error: stopping coroutine pool without native thread pool"),
-            };
-            let scheduler_handles = match self.scheduler_handles {
-                Some(ref scheduler_handles) => scheduler_handles,
-                None => return Err(CorosError::CannotStartPoolWithoutSchedulers),
-            };
-            for scheduler_handle in scheduler_handles {
-                if let Err(_) = scheduler_handle.shutdown_tx.send(()) {
-                    errors.push(CorosError::UnableToSendThreadShutdownSignal);
-                }
             }
-            thread_pool.join_and_stop();
-        }
-        {
-            let scheduler_result_rx = match self.scheduler_result_rx {
-                Some(ref scheduler_result_rx) => scheduler_result_rx,
-                None => return Err(CorosError::InvalidPoolNoSchedulerResultReceiver),
-            };
-            for _ in 0..self.thread_count {
-                if let Err(err) = scheduler_result_rx.recv() {
-                    errors.push(CorosError::UnableToReceiveThreadShutdownResult(err));
-                }
-            }
-        }
+        };
+        let errors = try!(self.shut_down_threads(thread_pool));
         self.is_running = false;
 
         try!(self.create_scheduler_handles());
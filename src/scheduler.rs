@@ -1,9 +1,24 @@
+use std::boxed::FnBox;
+use std::collections::{
+    HashMap,
+    VecDeque,
+};
 use std::sync::mpsc::{
     Receiver,
     Sender,
     TryRecvError,
 };
-use std::sync::Mutex;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+    Weak,
+};
+use std::sync::atomic::{
+    ATOMIC_USIZE_INIT,
+    AtomicUsize,
+    Ordering,
+};
 use std::time::Duration;
 
 use context::Context;
@@ -16,46 +31,278 @@ use slab::Slab;
 use mio::{
     EventLoop,
     EventSet,
+    Evented,
+    PollOpt,
     Token,
 };
 use mio::Handler as MioHandler;
+use mio::Sender as MioSender;
 
 use coroutine::Coroutine;
+use coroutine::channel::BlockedMessage;
+use coroutine::readiness::ReadinessQueue;
 use error::CorosError;
+use stack::{
+    self,
+    StackUsageHistogram,
+};
+use stack_cache::{
+    StackCache,
+    StackCacheLimits,
+};
 use Result;
 
-pub type BlockedCoroutineSlab = Slab<(Coroutine, Option<Sender<EventSet>>), Token>;
+pub type BlockedCoroutineSlab = Slab<Box<Coroutine>, Token>;
+
+/// The readiness queues that a scheduler's IO registrations deliver to.
+/// Shared with Registrations so they can remove themselves when dropped.
+pub type IoRegistrations = Arc<Mutex<HashMap<Token, Weak<Mutex<ReadinessQueue>>>>>;
+
+/// Wait ids are unique across all schedulers so that a wakeup meant for an
+/// earlier block can't resume a coroutine that has since reused its token
+static NEXT_WAIT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// IO registration tokens are unique across all schedulers so that a
+/// coroutine's readiness queue can key registrations made on different
+/// schedulers' event loops by token alone
+static NEXT_IO_TOKEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn next_io_token() -> Token {
+    Token(NEXT_IO_TOKEN.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Schedulers are numbered so that IO can be traced back to the scheduler
+/// whose event loop it's registered with
+static NEXT_SCHEDULER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sent through notify and timeout to wake a blocked coroutine
+#[derive(Clone, Copy, Debug)]
+pub struct Wakeup {
+    pub token: Token,
+    pub wait_id: usize,
+}
+
+/// Run by a scheduler against its own event loop and IO registrations, for
+/// IO registered there by a coroutine that's since moved to another thread
+pub type EventLoopOperation = Box<FnBox(&mut EventLoop<Scheduler>, &IoRegistrations) + Send>;
+
+/// The operations other threads have queued for a scheduler's event loop.
+/// None once the scheduler has shut down, operations still queued then are
+/// dropped without running.
+pub type IoOperationQueue = Arc<Mutex<Option<VecDeque<EventLoopOperation>>>>;
+
+/// Sent through notify
+#[derive(Clone, Copy, Debug)]
+pub enum SchedulerMessage {
+    RunIoOperations,
+    Wakeup(Wakeup),
+}
+
+/// The scheduler whose event loop some IO is registered with. The IO has to
+/// be reregistered and deregistered through that event loop, even once the
+/// coroutine that registered it has been stolen by another scheduler.
+#[derive(Clone)]
+pub struct IoOwner {
+    io_operations: IoOperationQueue,
+    mio_tx: MioSender<SchedulerMessage>,
+    scheduler_id: usize,
+}
+
+impl IoOwner {
+    pub fn scheduler_id(&self) -> usize {
+        self.scheduler_id
+    }
+
+    /// Runs the operation on the owning scheduler's thread, the next time
+    /// its event loop ticks. Fails with IoOwnerShutDown if the owner has
+    /// already shut down, and an operation still queued when it shuts down
+    /// is dropped without running.
+    pub fn send(&self, operation: EventLoopOperation) -> Result<()> {
+        match *lock_io_operations(&self.io_operations) {
+            Some(ref mut io_operations) => io_operations.push_back(operation),
+            None => return Err(CorosError::IoOwnerShutDown),
+        }
+
+        // The queue is run every time the event loop ticks anyway, notifying
+        // only saves waiting for the tick, so a full channel isn't an error
+        let _ = self.mio_tx.send(SchedulerMessage::RunIoOperations);
+
+        Ok(())
+    }
+}
+
+fn lock_io_operations(io_operations: &IoOperationQueue) -> MutexGuard<Option<VecDeque<EventLoopOperation>>> {
+    io_operations
+        .lock()
+        .expect("Coros internal error: io operations lock poisoned")
+}
+
+/// Runs the operations queued for the event loop by other threads
+fn run_io_operations(
+    io_operations: &IoOperationQueue,
+    mio_event_loop: &mut EventLoop<Scheduler>,
+    io_registrations: &IoRegistrations,
+) {
+    let queued_operations: Vec<EventLoopOperation> = match *lock_io_operations(io_operations) {
+        Some(ref mut io_operations) => io_operations.drain(..).collect(),
+        None => return,
+    };
+
+    for operation in queued_operations {
+        operation.call_box((mio_event_loop, io_registrations));
+    }
+}
+
+/// Registers the IO with the event loop, its readiness is pushed onto the
+/// coroutine's readiness queue as it arrives
+pub fn register_io<E: ?Sized>(
+    mio_event_loop: &mut EventLoop<Scheduler>,
+    io_registrations: &IoRegistrations,
+    io: &E,
+    io_token: Token,
+    interest: EventSet,
+    opt: PollOpt,
+    readiness: &Arc<Mutex<ReadinessQueue>>,
+) -> Result<()>
+    where E: Evented
+{
+    try!(mio_event_loop.register(io, io_token, interest, opt));
+    io_registrations
+        .lock()
+        .expect("Coros internal error: io registrations lock poisoned")
+        .insert(io_token, Arc::downgrade(readiness));
+
+    Ok(())
+}
+
+pub fn reregister_io<E: ?Sized>(
+    mio_event_loop: &mut EventLoop<Scheduler>,
+    io_registrations: &IoRegistrations,
+    io: &E,
+    io_token: Token,
+    interest: EventSet,
+    opt: PollOpt,
+    readiness: &Arc<Mutex<ReadinessQueue>>,
+) -> Result<()>
+    where E: Evented
+{
+    try!(mio_event_loop.reregister(io, io_token, interest, opt));
+    io_registrations
+        .lock()
+        .expect("Coros internal error: io registrations lock poisoned")
+        .insert(io_token, Arc::downgrade(readiness));
+
+    Ok(())
+}
+
+pub fn deregister_io<E: ?Sized>(
+    mio_event_loop: &mut EventLoop<Scheduler>,
+    io_registrations: &IoRegistrations,
+    io: &E,
+    maybe_io_token: Option<Token>,
+) -> Result<()>
+    where E: Evented
+{
+    try!(mio_event_loop.deregister(io));
+    if let Some(io_token) = maybe_io_token {
+        io_registrations
+            .lock()
+            .expect("Coros internal error: io registrations lock poisoned")
+            .remove(&io_token);
+    }
+
+    Ok(())
+}
+
+/// Moves a suspended coroutine into the blocked slab, returning the message
+/// needed to wake it. If a timeout is given the coroutine is woken with
+/// timed_out set once it passes. If the coroutine was cancelled before it
+/// was blocked it's woken straight away.
+pub fn block_coroutine(
+    coroutine: Box<Coroutine>,
+    maybe_timeout: Option<Duration>,
+    mio_event_loop: &mut EventLoop<Scheduler>,
+    blocked_coroutines: &mut BlockedCoroutineSlab,
+) -> Result<BlockedMessage> {
+    let mut coroutine = coroutine;
+    let wait_id = NEXT_WAIT_ID.fetch_add(1, Ordering::SeqCst);
+    coroutine.wait_id = wait_id;
+    coroutine.timed_out = false;
+    let cancellation = coroutine.cancellation.clone();
+
+    let token = match blocked_coroutines.insert(coroutine) {
+        Ok(token) => token,
+        Err(_) => return Err(CorosError::SlabFull),
+    };
+    let blocked_message = BlockedMessage {
+        mio_tx: mio_event_loop.channel(),
+        token: token,
+        wait_id: wait_id,
+    };
+
+    if let Some(timeout) = maybe_timeout {
+        let timer = try!(mio_event_loop.timeout(blocked_message.wakeup(), timeout));
+        if let Some(coroutine) = blocked_coroutines.get_mut(token) {
+            coroutine.timer = Some(timer);
+        }
+    }
+
+    try!(
+        cancellation
+            .lock()
+            .expect("Coros internal error: cancellation lock poisoned")
+            .park(blocked_message.clone())
+    );
+
+    Ok(blocked_message)
+}
 
 pub struct Scheduler {
     blocked_coroutines: BlockedCoroutineSlab,
+    default_stack_size: usize,
+    id: usize,
+    io_operations: IoOperationQueue,
+    io_registrations: IoRegistrations,
+    is_idle: bool,
     is_shutting_down: bool,
     mio_event_loop: EventLoop<Scheduler>,
+    pool_name: String,
     result_tx: Sender<Result<()>>,
     scheduler_context: Context,
     shutdown_rx: Receiver<()>,
-    work_provider: Worker<Coroutine>,
-    work_rx: Receiver<Coroutine>,
-    work_stealers: Mutex<Vec<Stealer<Coroutine>>>,
+    stack_cache: StackCache,
+    stack_usage: Option<Arc<Mutex<StackUsageHistogram>>>,
+    work_provider: Worker<Box<Coroutine>>,
+    work_rx: Receiver<Box<Coroutine>>,
+    work_stealers: Mutex<Vec<Stealer<Box<Coroutine>>>>,
+    yielded_coroutines: Vec<Box<Coroutine>>,
 }
 
 impl MioHandler for Scheduler {
-    type Timeout = Token;
-    type Message = Token;
+    type Timeout = Wakeup;
+    type Message = SchedulerMessage;
 
-    fn notify(&mut self, _: &mut EventLoop<Scheduler>, coroutine_token: Token) {
-        if let Err(err) = self.enqueue_coroutine(coroutine_token, None) {
-          error!("Error notifying coroutine of IO: {:?}", err);
+    fn notify(&mut self, mio_event_loop: &mut EventLoop<Scheduler>, message: SchedulerMessage) {
+        match message {
+            SchedulerMessage::RunIoOperations => {
+                run_io_operations(&self.io_operations, mio_event_loop, &self.io_registrations)
+            },
+            SchedulerMessage::Wakeup(wakeup) => {
+                if let Err(err) = self.wake_coroutine(wakeup) {
+                  error!("Error notifying coroutine of IO: {:?}", err);
+                }
+            },
         }
     }
 
-    fn ready(&mut self, _: &mut EventLoop<Scheduler>, coroutine_token: Token, eventset: EventSet) {
-        if let Err(err) = self.enqueue_coroutine(coroutine_token, Some(eventset)) {
+    fn ready(&mut self, _: &mut EventLoop<Scheduler>, io_token: Token, eventset: EventSet) {
+        if let Err(err) = self.queue_readiness(io_token, eventset) {
           error!("Error readying coroutine for IO: {:?}", err);
         }
     }
 
-    fn timeout(&mut self, _: &mut EventLoop<Scheduler>, coroutine_token: Token) {
-        if let Err(err) = self.enqueue_coroutine(coroutine_token, None) {
+    fn timeout(&mut self, _: &mut EventLoop<Scheduler>, wakeup: Wakeup) {
+        if let Err(err) = self.time_out_coroutine(wakeup) {
           error!("Error awakening coroutine after timer alert: {:?}", err);
         }
     }
@@ -65,28 +312,115 @@ const MAX_STOLEN_WORK_BATCH_SIZE: usize = 1000;
 
 impl Scheduler {
     pub fn new(
+        blocked_coroutine_capacity: usize,
+        default_stack_size: usize,
+        pool_name: String,
         result_tx: Sender<Result<()>>,
         shutdown_rx: Receiver<()>,
-        work_provider: Worker<Coroutine>,
-        work_rx: Receiver<Coroutine>,
-        work_stealers: Vec<Stealer<Coroutine>>,
+        stack_cache_limits: StackCacheLimits,
+        stack_usage: Option<Arc<Mutex<StackUsageHistogram>>>,
+        work_provider: Worker<Box<Coroutine>>,
+        work_rx: Receiver<Box<Coroutine>>,
+        work_stealers: Vec<Stealer<Box<Coroutine>>>,
     ) -> Result<Scheduler> {
         Ok(Scheduler {
-            blocked_coroutines: Slab::new(1024 * 64),
+            blocked_coroutines: Slab::new(blocked_coroutine_capacity),
+            default_stack_size: default_stack_size,
+            id: NEXT_SCHEDULER_ID.fetch_add(1, Ordering::SeqCst),
+            io_operations: Arc::new(Mutex::new(Some(VecDeque::new()))),
+            io_registrations: Arc::new(Mutex::new(HashMap::new())),
+            is_idle: false,
             is_shutting_down: false,
             mio_event_loop: try!(EventLoop::new()),
+            pool_name: pool_name,
             result_tx: result_tx,
             scheduler_context: Context::empty(),
             shutdown_rx: shutdown_rx,
+            stack_cache: StackCache::new(stack_cache_limits),
+            stack_usage: stack_usage,
             work_provider: work_provider,
             work_rx: work_rx,
             work_stealers: Mutex::new(work_stealers),
+            yielded_coroutines: Vec::new(),
         })
     }
 
-    fn run_coroutine(&mut self, coroutine: Coroutine) -> Result<()> {
+    pub fn scheduler_context(&self) -> &Context {
+        &self.scheduler_context
+    }
+
+    pub fn default_stack_size(&self) -> usize {
+        self.default_stack_size
+    }
+
+    pub fn pool_name(&self) -> &str {
+        &self.pool_name
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn io_registrations(&self) -> &IoRegistrations {
+        &self.io_registrations
+    }
+
+    /// Identifies this scheduler as the owner of IO registered with its
+    /// event loop
+    pub fn io_owner(&self) -> IoOwner {
+        IoOwner {
+            io_operations: self.io_operations.clone(),
+            mio_tx: self.mio_event_loop.channel(),
+            scheduler_id: self.id,
+        }
+    }
+
+    /// Runs the operation against this scheduler's event loop straight away
+    pub fn run_io_operation<F, T>(&mut self, operation: F) -> T
+        where F: FnOnce(&mut EventLoop<Scheduler>, &IoRegistrations) -> T
+    {
+        operation(&mut self.mio_event_loop, &self.io_registrations)
+    }
+
+    pub fn stack_cache_mut(&mut self) -> &mut StackCache {
+        &mut self.stack_cache
+    }
+
+    /// The pool's stack usage histogram, if it's measuring stack usage
+    pub fn stack_usage(&self) -> Option<&Arc<Mutex<StackUsageHistogram>>> {
+        self.stack_usage.as_ref()
+    }
+
+    /// Registers the IO with this scheduler's event loop
+    pub fn register<E: ?Sized>(
+        &mut self,
+        io: &E,
+        io_token: Token,
+        interest: EventSet,
+        opt: PollOpt,
+        readiness: &Arc<Mutex<ReadinessQueue>>,
+    ) -> Result<()>
+        where E: Evented
+    {
+        register_io(
+            &mut self.mio_event_loop,
+            &self.io_registrations,
+            io,
+            io_token,
+            interest,
+            opt,
+            readiness,
+        )
+    }
+
+    pub fn push_work(&self, coroutine: Box<Coroutine>) {
+        self.work_provider.push(coroutine);
+    }
+
+    fn run_coroutine(&mut self, coroutine: Box<Coroutine>) -> Result<()> {
         let mut coroutine = coroutine;
-        try!(coroutine.run(&self.scheduler_context));
+        let raw_self_ptr: *mut Scheduler = self;
+        try!(coroutine.run(raw_self_ptr));
 
         if coroutine.blocked() {
             match coroutine.event_loop_registration.take() {
@@ -99,22 +433,40 @@ impl Scheduler {
                 ),
                 None => return Err(CorosError::InvalidCoroutineNoCallback),
             }
+        } else if coroutine.yielded() {
+            self.yielded_coroutines.push(coroutine);
+        } else if coroutine.finished() && self.stack_usage.is_none() {
+            // Measuring needs freshly painted stacks, so they aren't reused
+            if let Some(stack) = coroutine.take_stack() {
+                self.stack_cache.give(coroutine.stack_size, stack);
+            }
         }
 
         Ok(())
     }
 
     pub fn run(&mut self) {
+        stack::install_overflow_handler();
         let result_tx = self.result_tx.clone();
+        let result = self.run_eventloop();
+        self.shut_down_io_operations();
         result_tx
-            .send(self.run_eventloop())
+            .send(result)
             .expect("Coros internal error: attempting to send thread scheduler result to closed channel");
     }
 
+    /// Stops taking operations from other threads, dropping the ones still
+    /// queued so the coroutines waiting on them are woken with an error
+    fn shut_down_io_operations(&mut self) {
+        let maybe_io_operations = lock_io_operations(&self.io_operations).take();
+        drop(maybe_io_operations);
+    }
+
     pub fn run_eventloop(&mut self) -> Result<()> {
         'event_loop:
         while !self.ready_to_shutdown() {
             try!(self.move_received_work_onto_queue());
+            run_io_operations(&self.io_operations, &mut self.mio_event_loop, &self.io_registrations);
 
             let raw_self_ptr: *mut Scheduler = self;
             let event_loop_tick_timeout = Some(Duration::from_millis(10));
@@ -123,17 +475,20 @@ impl Scheduler {
                     event_loop_tick_timeout
             ));
 
-            let work_result = match self.work_provider.pop() {
-                Some(coroutine) => self.run_coroutine(coroutine),
-                None => {
-                    match try!(self.stolen_work()) {
-                        Some(coroutine) => self.run_coroutine(coroutine),
-                        None => Ok(()),
-                    }
-                },
+            let maybe_coroutine = match self.work_provider.pop() {
+                Some(coroutine) => Some(coroutine),
+                None => try!(self.stolen_work()),
             };
 
-            try!(work_result);
+            // Yielded coroutines are only requeued after the next piece of
+            // work is picked, otherwise popping the deque would hand the
+            // yielded coroutine straight back
+            self.is_idle = maybe_coroutine.is_none() && self.yielded_coroutines.is_empty();
+            self.requeue_yielded_coroutines();
+
+            if let Some(coroutine) = maybe_coroutine {
+                try!(self.run_coroutine(coroutine));
+            }
         };
 
         Ok(())
@@ -144,10 +499,13 @@ impl Scheduler {
             self.is_shutting_down = true
         }
 
-        self.is_shutting_down && self.blocked_coroutines.is_empty()
+        self.is_shutting_down &&
+            self.is_idle &&
+            self.blocked_coroutines.is_empty() &&
+            self.yielded_coroutines.is_empty()
     }
 
-    pub fn stolen_work(&mut self) -> Result<Option<Coroutine>> {
+    pub fn stolen_work(&mut self) -> Result<Option<Box<Coroutine>>> {
         let ref work_stealers = try!(self.work_stealers.lock());
         for work_stealer in work_stealers.iter() {
             if let Stolen::Data(coroutine) = work_stealer.steal() {
@@ -170,70 +528,102 @@ impl Scheduler {
         Ok(())
     }
 
-    fn enqueue_coroutine(&mut self, coroutine_token: Token, maybe_eventset: Option<EventSet>) -> Result<()> {
-        let blocked_on_io = try!(self.blocked_on_io(coroutine_token));
-        let awoken_for_io = maybe_eventset.is_some();
-
-        match (blocked_on_io, awoken_for_io) {
-            (true, true) => {
-                // Blocked on IO, awoken for IO
-
-                let maybe_coroutine_slab_contents = self
-                    .blocked_coroutines
-                    .remove(coroutine_token);
-                let (coroutine, maybe_eventset_tx) = match maybe_coroutine_slab_contents {
-                    Some(coroutine_slab_contents) => coroutine_slab_contents,
-                    None => return Err(CorosError::MissingCoroutine),
-                };
-                let eventset_tx = match maybe_eventset_tx {
-                    Some(eventset_tx) => eventset_tx,
-                    None => return Err(CorosError::InvalidCoroutineSlabContents),
-                };
-                let eventset = match maybe_eventset {
-                    Some(eventset) => eventset,
-                    None => panic!("Coros internal error: eventset changed in an impossible way"),
-                };
-
-                if let Err(_) = eventset_tx.send(eventset) {
-                    return Err(CorosError::SendIoResultToCoroutineError)
-                }
+    fn requeue_yielded_coroutines(&mut self) {
+        for coroutine in self.yielded_coroutines.drain(..) {
+            self.work_provider.push(coroutine);
+        }
+    }
 
-                self.work_provider.push(coroutine);
-            },
-            (true, false) => {
-                // Blocked on IO, awoken for not-IO
+    fn wake_coroutine(&mut self, wakeup: Wakeup) -> Result<()> {
+        let is_current_wait = match self.blocked_coroutines.get(wakeup.token) {
+            Some(coroutine) => coroutine.wait_id == wakeup.wait_id,
+            None => false,
+        };
 
-                return Err(CorosError::CoroutineBlockedOnIoAwokenForNotIo)
-            },
-            (false, true) => {
-                // Blocked on not-IO, awoken for IO
+        // Stale wakeups for coroutines that have already been resumed are
+        // dropped, the token may now belong to another coroutine
+        if !is_current_wait {
+            return Ok(())
+        }
 
-                // Nooping for now, but coroutines should have a prexisting eventset
-                // queue where these can build up and can be returned from non-IO blocks
-            },
-            (false, false) => {
-                // Blocked on not-IO, awoken for not-IO
-
-                let maybe_coroutine_slab_contents = self
-                    .blocked_coroutines
-                    .remove(coroutine_token);
-                let (coroutine, _) = match maybe_coroutine_slab_contents {
-                    Some(coroutine_slab_contents) => coroutine_slab_contents,
-                    None => return Err(CorosError::MissingCoroutine),
-                };
-
-                self.work_provider.push(coroutine);
+        self.enqueue_coroutine(wakeup.token)
+    }
+
+    fn time_out_coroutine(&mut self, wakeup: Wakeup) -> Result<()> {
+        let is_current_wait = match self.blocked_coroutines.get_mut(wakeup.token) {
+            Some(coroutine) => {
+                if coroutine.wait_id == wakeup.wait_id {
+                    coroutine.timed_out = true;
+                    coroutine.timer = None;
+                    true
+                } else {
+                    false
+                }
             },
+            None => false,
+        };
+
+        if !is_current_wait {
+            return Ok(())
+        }
+
+        self.enqueue_coroutine(wakeup.token)
+    }
+
+    /// Readiness for a coroutine that has finished is dropped along with
+    /// the registration
+    fn queue_readiness(&mut self, io_token: Token, eventset: EventSet) -> Result<()> {
+        let maybe_readiness = {
+            let mut io_registrations = self.io_registrations
+                .lock()
+                .expect("Coros internal error: io registrations lock poisoned");
+            let maybe_readiness = io_registrations
+                .get(&io_token)
+                .and_then(|readiness| readiness.upgrade());
+            if maybe_readiness.is_none() {
+                io_registrations.remove(&io_token);
+            }
+
+            maybe_readiness
         };
 
+        match maybe_readiness {
+            Some(readiness) => {
+                readiness
+                    .lock()
+                    .expect("Coros internal error: readiness queue lock poisoned")
+                    .push(io_token, eventset)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn enqueue_coroutine(&mut self, coroutine_token: Token) -> Result<()> {
+        let coroutine = try!(self.unblock_coroutine(coroutine_token));
+        self.work_provider.push(coroutine);
+
         Ok(())
     }
 
-    fn blocked_on_io(&self, coroutine_token: Token) -> Result<bool> {
-        let maybe_coroutine_slab_contents = self.blocked_coroutines.get(coroutine_token);
-        match maybe_coroutine_slab_contents {
-            Some(&(_, ref maybe_eventset_tx)) => Ok(maybe_eventset_tx.is_some()),
-            None => Err(CorosError::MissingCoroutine),
+    /// Removes a coroutine from the blocked slab, disarming any timer it
+    /// was blocked with
+    fn unblock_coroutine(&mut self, coroutine_token: Token) -> Result<Box<Coroutine>> {
+        let mut coroutine = match self.blocked_coroutines.remove(coroutine_token) {
+            Some(coroutine) => coroutine,
+            None => return Err(CorosError::MissingCoroutine),
+        };
+        if let Some(timer) = coroutine.timer.take() {
+            self.mio_event_loop.clear_timeout(timer);
         }
+
+        Ok(coroutine)
+    }
+}
+
+/// A scheduler that panicked never reaches the end of run, its queued
+/// operations are still dropped so their coroutines aren't left waiting
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shut_down_io_operations();
     }
 }
@@ -0,0 +1,91 @@
+use std::boxed::FnBox;
+use std::cell::{
+    Cell,
+    RefCell,
+};
+use std::marker::PhantomData;
+use std::mem;
+use std::panic;
+
+use Result;
+use coroutine::io_handle::IoHandle;
+use coroutine::join_handle::JoinHandle;
+use error::{
+    CoroutinePanicDetails,
+    CorosError,
+};
+use spawner::Spawner;
+
+/// A scoped coroutine body with its lifetime erased so it can be spawned
+/// like any other. Scope::spawn already requires the body to be
+/// RecoverSafe.
+struct ScopedBody(Box<FnBox(IoHandle) + Send>);
+
+impl panic::RecoverSafe for ScopedBody {}
+
+/// Spawns coroutines that can borrow from the stack of whoever called
+/// Pool::scope. Every coroutine spawned is joined before Pool::scope
+/// returns, or unwinds, so they can't outlive what they borrow.
+pub struct Scope<'pool, 'scope> {
+    join_handles: RefCell<Vec<JoinHandle<()>>>,
+    spawner: Spawner,
+    _marker: PhantomData<(&'pool (), Cell<&'scope mut ()>)>,
+}
+
+/// Only Pool::scope creates scopes, a scope that was leaked instead of
+/// dropped wouldn't join its coroutines
+pub fn new_scope<'pool, 'scope>(spawner: Spawner) -> Scope<'pool, 'scope> {
+    Scope {
+        join_handles: RefCell::new(Vec::new()),
+        spawner: spawner,
+        _marker: PhantomData,
+    }
+}
+
+/// Joins every coroutine spawned in the scope so far, returning the first
+/// panic among them
+pub fn join_scope(scope: &Scope) -> Option<CoroutinePanicDetails> {
+    let join_handles = mem::replace(&mut *scope.join_handles.borrow_mut(), Vec::new());
+
+    let mut maybe_panic = None;
+    for mut join_handle in join_handles {
+        match join_handle.join() {
+            Ok(Err(CorosError::CoroutinePanic(details))) => {
+                if maybe_panic.is_none() {
+                    maybe_panic = Some(details);
+                }
+            },
+            Ok(_) => (),
+            Err(err) => error!("Error joining scoped coroutine: {:?}", err),
+        }
+    }
+
+    maybe_panic
+}
+
+impl<'pool, 'scope> Scope<'pool, 'scope> {
+    /// Spawns a coroutine that can borrow anything that outlives the scope
+    pub fn spawn<F>(&self, coroutine_body: F, stack_size: usize) -> Result<()>
+        where F: FnOnce(IoHandle) + panic::RecoverSafe + Send + 'scope
+    {
+        let coroutine_body: Box<FnBox(IoHandle) + Send + 'scope> = Box::new(coroutine_body);
+        let scoped_body = ScopedBody(unsafe { mem::transmute(coroutine_body) });
+
+        let join_handle = try!(self.spawner.spawn(move |coroutine_handle: IoHandle| {
+            let ScopedBody(coroutine_body) = scoped_body;
+            coroutine_body.call_box((coroutine_handle,))
+        }, stack_size));
+        self.join_handles.borrow_mut().push(join_handle);
+
+        Ok(())
+    }
+}
+
+/// Joins any coroutines still outstanding, which only happens if the scope
+/// is unwinding. Their panics are dropped since the thread is already
+/// panicking.
+impl<'pool, 'scope> Drop for Scope<'pool, 'scope> {
+    fn drop(&mut self) {
+        join_scope(self);
+    }
+}
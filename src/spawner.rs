@@ -0,0 +1,93 @@
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::mpsc::Sender;
+
+use rand::{
+    Rng,
+    thread_rng,
+};
+
+use Result;
+use coroutine::Coroutine;
+use coroutine::io_handle::IoHandle;
+use coroutine::join_handle::JoinHandle;
+use error::CorosError;
+
+/// A handle for spawning coroutines onto a pool that can be cloned and shared
+/// between threads. The pool swaps in new work senders whenever it rebuilds its
+/// schedulers, so a spawner stays valid across pool stops and starts.
+#[derive(Clone)]
+pub struct Spawner {
+    default_stack_size: usize,
+    work_txs: Arc<Mutex<Vec<Sender<Box<Coroutine>>>>>,
+}
+
+impl Spawner {
+    pub fn new(
+        work_txs: Arc<Mutex<Vec<Sender<Box<Coroutine>>>>>,
+        default_stack_size: usize,
+    ) -> Spawner {
+        Spawner {
+            default_stack_size: default_stack_size,
+            work_txs: work_txs,
+        }
+    }
+
+    pub fn spawn_with_thread_index<F, T>(
+        &self,
+        coroutine_body: F,
+        stack_size: usize,
+        thread_index: u32
+    ) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        let work_txs = try!(self.work_txs.lock());
+        let work_tx = match work_txs.get(thread_index as usize) {
+            None => {
+                return Err(CorosError::InvalidThreadForSpawn(thread_index, work_txs.len() as u32))
+            }
+            Some(work_tx) => work_tx,
+        };
+
+        let (coroutine, join_handle) = Coroutine::with_join_handle(
+            coroutine_body,
+            stack_size,
+        );
+
+        if let Err(_) = work_tx.send(coroutine) {
+          return Err(CorosError::TriedToSpawnCoroutineOnShutdownThread)
+        }
+
+        Ok(join_handle)
+    }
+
+    pub fn spawn<F, T>(&self, coroutine_body: F, stack_size: usize) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        let thread_count = try!(self.work_txs.lock()).len();
+        if thread_count == 0 {
+            return Err(CorosError::CannotStartPoolWithoutSchedulers)
+        }
+
+        self.spawn_with_thread_index(
+            coroutine_body,
+            stack_size,
+            thread_rng().gen_range(0, thread_count) as u32,
+        )
+    }
+
+    pub fn spawn_with_default_stack<F, T>(&self, coroutine_body: F) -> Result<JoinHandle<T>>
+        where F: FnOnce(IoHandle) -> T + panic::RecoverSafe + Send + 'static,
+              T: Send + 'static,
+    {
+        self.spawn(coroutine_body, self.default_stack_size)
+    }
+}
+
+impl panic::RecoverSafe for Spawner {}
+impl panic::RefRecoverSafe for Spawner {}
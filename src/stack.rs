@@ -0,0 +1,384 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::{
+    self,
+    Write,
+};
+use std::io::Error as IoError;
+use std::mem;
+use std::ptr;
+use std::sync::{
+    ONCE_INIT,
+    Once,
+};
+
+use context::{
+    Context,
+    Stack,
+};
+use libc::{
+    self,
+    c_int,
+    c_void,
+};
+
+use coroutine;
+use error::CorosError;
+use stack_cache::size_class;
+use Result;
+
+/// Big enough for the overflow handler to format and write its message
+const SIGNAL_STACK_SIZE: usize = 64 * 1024;
+
+/// Painted over new stacks when measuring stack usage. Bytes a coroutine
+/// happens to write with the same value aren't counted as touched, so usage
+/// can be under reported by a few bytes.
+const STACK_CANARY: u8 = 0xC5;
+
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The protected page at the bottom of a coroutine's stack. Stacks grow
+/// down, so a coroutine that overflows its stack faults on this page rather
+/// than writing over whatever memory is below it.
+#[derive(Debug)]
+pub struct GuardPage {
+    start: usize,
+    end: usize,
+}
+
+impl GuardPage {
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+/// The memory a coroutine stack lives in, mapped by coros rather than the
+/// context crate so that its lowest page can be made the guard page
+struct StackMapping {
+    guard_page: GuardPage,
+    len: usize,
+    start: usize,
+}
+
+impl StackMapping {
+    /// Maps the stack size rounded up to whole pages, plus the guard page
+    /// below it
+    fn new(stack_size: usize) -> Result<StackMapping> {
+        let page_size = page_size();
+        let len = ((stack_size + page_size - 1) & !(page_size - 1)) + page_size;
+        let start = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        if start == libc::MAP_FAILED {
+            return Err(CorosError::UnableToMapStack(IoError::last_os_error()))
+        }
+
+        let mapping = StackMapping {
+            guard_page: GuardPage {
+                start: start as usize,
+                end: start as usize + page_size,
+            },
+            len: len,
+            start: start as usize,
+        };
+        let protect_result = unsafe {
+            libc::mprotect(start, page_size, libc::PROT_NONE)
+        };
+        if protect_result != 0 {
+            return Err(CorosError::UnableToProtectStackGuardPage(IoError::last_os_error()))
+        }
+
+        Ok(mapping)
+    }
+
+    /// The lowest address the stack can use, just above the guard page
+    fn bottom(&self) -> usize {
+        self.guard_page.end
+    }
+
+    fn top(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+impl Drop for StackMapping {
+    fn drop(&mut self) {
+        let unmap_result = unsafe { libc::munmap(self.start as *mut c_void, self.len) };
+        if unmap_result != 0 {
+            error!("Error unmapping coroutine stack: {:?}", IoError::last_os_error());
+        }
+    }
+}
+
+/// A coroutine context and the stack it runs on. Contexts are kept with
+/// their stacks for reuse, so the stack stays mapped until the context is
+/// done with.
+pub struct CoroutineStack {
+    // Declared before the mapping so the context is dropped before the
+    // stack it runs on is unmapped
+    pub context: Context,
+    is_painted: bool,
+    mapping: StackMapping,
+}
+
+impl CoroutineStack {
+    /// Maps a stack of at least the size asked for, with a guard page below
+    /// it. If the stack is painted its usage can be measured, it's painted
+    /// before the context is set up on it.
+    pub fn new(
+        context_init: extern "C" fn(usize, usize) -> !,
+        context_arg: usize,
+        stack_size: usize,
+        is_painted: bool,
+    ) -> Result<CoroutineStack> {
+        let mapping = try!(StackMapping::new(stack_size));
+        if is_painted {
+            unsafe {
+                ptr::write_bytes(
+                    mapping.bottom() as *mut u8,
+                    STACK_CANARY,
+                    mapping.top() - mapping.bottom(),
+                );
+            }
+        }
+
+        // The context only borrows the memory, the mapping unmaps it
+        let stack = unsafe {
+            Stack::from_raw(mapping.bottom() as *mut u8, mapping.top() - mapping.bottom())
+        };
+
+        Ok(CoroutineStack {
+            context: Context::new(context_init, context_arg, 0 as usize, stack),
+            is_painted: is_painted,
+            mapping: mapping,
+        })
+    }
+
+    pub fn guard_page(&self) -> &GuardPage {
+        &self.mapping.guard_page
+    }
+
+    /// How many bytes of the stack have been touched since it was painted,
+    /// found by scanning up from the bottom for the first byte that isn't
+    /// the canary. None if the stack wasn't painted.
+    pub fn high_water_mark(&self) -> Option<usize> {
+        if !self.is_painted {
+            return None
+        }
+
+        let top = self.mapping.top();
+        let mut deepest_untouched = self.mapping.bottom();
+        while deepest_untouched < top &&
+            unsafe { *(deepest_untouched as *const u8) } == STACK_CANARY {
+            deepest_untouched += 1;
+        }
+
+        Some(top - deepest_untouched)
+    }
+}
+
+/// How deep finished coroutines' stacks got, bucketed by the power of two
+/// stack size that would have fit them
+#[derive(Clone, Debug)]
+pub struct StackUsageHistogram {
+    buckets: BTreeMap<usize, usize>,
+    count: usize,
+    max: usize,
+}
+
+impl StackUsageHistogram {
+    pub fn new() -> StackUsageHistogram {
+        StackUsageHistogram {
+            buckets: BTreeMap::new(),
+            count: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, high_water_mark: usize) {
+        *self.buckets.entry(size_class(high_water_mark)).or_insert(0) += 1;
+        self.count += 1;
+        self.max = cmp::max(self.max, high_water_mark);
+    }
+
+    /// Stack sizes in bytes, smallest first, with how many coroutines used
+    /// more than the size below but no more than that size
+    pub fn buckets(&self) -> Vec<(usize, usize)> {
+        self.buckets
+            .iter()
+            .map(|(stack_size, count)| (*stack_size, *count))
+            .collect()
+    }
+
+    /// How many coroutines have been measured
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The deepest any measured coroutine's stack got, in bytes
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+static INSTALL_OVERFLOW_HANDLER: Once = ONCE_INIT;
+static mut PREVIOUS_SIGSEGV_ACTION: *const libc::sigaction = 0 as *const libc::sigaction;
+static mut PREVIOUS_SIGBUS_ACTION: *const libc::sigaction = 0 as *const libc::sigaction;
+
+/// Installs the process wide fault handler that reports coroutine stack
+/// overflows, and gives the calling scheduler thread an alternate signal
+/// stack to run it on, since the coroutine's own stack is exhausted
+pub fn install_overflow_handler() {
+    INSTALL_OVERFLOW_HANDLER.call_once(|| unsafe {
+        PREVIOUS_SIGSEGV_ACTION = replace_fault_action(libc::SIGSEGV);
+        PREVIOUS_SIGBUS_ACTION = replace_fault_action(libc::SIGBUS);
+    });
+
+    unsafe { install_signal_stack() };
+}
+
+unsafe fn replace_fault_action(signum: c_int) -> *const libc::sigaction {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handle_fault as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    let mut previous_action: libc::sigaction = mem::zeroed();
+    libc::sigaction(signum, &action, &mut previous_action);
+
+    Box::into_raw(Box::new(previous_action))
+}
+
+/// Threads spawned by std already have a signal stack, which is kept. The
+/// one mapped here lives as long as the thread.
+unsafe fn install_signal_stack() {
+    let mut current_stack: libc::stack_t = mem::zeroed();
+    libc::sigaltstack(ptr::null(), &mut current_stack);
+    if current_stack.ss_flags & libc::SS_DISABLE == 0 {
+        return
+    }
+
+    let signal_stack_size = cmp::max(SIGNAL_STACK_SIZE, page_size());
+    let signal_stack = libc::mmap(
+        ptr::null_mut(),
+        signal_stack_size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANON,
+        -1,
+        0,
+    );
+    if signal_stack == libc::MAP_FAILED {
+        error!(
+            "Error mapping signal stack, stack overflows won't be reported: {:?}",
+            IoError::last_os_error()
+        );
+        return
+    }
+
+    let stack = libc::stack_t {
+        ss_sp: signal_stack,
+        ss_flags: 0,
+        ss_size: signal_stack_size,
+    };
+    libc::sigaltstack(&stack, ptr::null_mut());
+}
+
+/// Aborts with a report if the fault hit the running coroutine's guard
+/// page. Any other fault is handed back to the handler that was installed
+/// before, by restoring it and returning to let the fault happen again.
+/// Only allocation free, lock free work is safe here, so the overflow can't
+/// be passed on to the coroutine's JoinHandle, the process is aborted
+/// before anything could receive it anyway. Only the running coroutine's
+/// guard page is checked, a generator overflowing its own stack faults as
+/// it would without the handler.
+extern "C" fn handle_fault(signum: c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    let fault_address = unsafe { fault_address(info) };
+    if let Some(coroutine) = coroutine::maybe_running_coroutine() {
+        let is_overflow = coroutine
+            .guard_page()
+            .map_or(false, |guard_page| guard_page.contains(fault_address));
+        if is_overflow {
+            report_overflow(coroutine.scheduler().pool_name(), coroutine.id);
+            unsafe { libc::abort() };
+        }
+    }
+
+    unsafe {
+        let previous_action = if signum == libc::SIGBUS {
+            PREVIOUS_SIGBUS_ACTION
+        } else {
+            PREVIOUS_SIGSEGV_ACTION
+        };
+        libc::sigaction(signum, previous_action, ptr::null_mut());
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn fault_address(info: *const libc::siginfo_t) -> usize {
+    // libc doesn't expose si_addr on Linux, where it follows the signal
+    // number, errno and code
+    #[repr(C)]
+    struct FaultSiginfo {
+        _si_signo: c_int,
+        _si_errno: c_int,
+        _si_code: c_int,
+        si_addr: *const c_void,
+    }
+
+    (*(info as *const FaultSiginfo)).si_addr as usize
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn fault_address(info: *const libc::siginfo_t) -> usize {
+    (*info).si_addr as usize
+}
+
+/// Formats into a fixed buffer, since the handler can't allocate
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == self.bytes.len() {
+                break
+            }
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn report_overflow(pool_name: &str, coroutine_id: usize) {
+    let mut message = MessageBuffer {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(
+        message,
+        "Coroutine {} in pool {} overflowed its stack, aborting\n",
+        coroutine_id,
+        pool_name
+    );
+
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            message.bytes.as_ptr() as *const c_void,
+            message.len,
+        );
+    }
+}
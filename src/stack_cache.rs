@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use stack::CoroutineStack;
+
+pub const DEFAULT_MAX_CACHED_STACKS_PER_SIZE_CLASS: usize = 16;
+pub const DEFAULT_MAX_CACHED_STACK_BYTES: usize = 64 * 1024 * 1024;
+
+/// How many finished coroutines' stacks each scheduler keeps for reuse
+#[derive(Clone, Copy, Debug)]
+pub struct StackCacheLimits {
+    pub max_stacks_per_size_class: usize,
+    pub max_bytes: usize,
+}
+
+impl StackCacheLimits {
+    pub fn new() -> StackCacheLimits {
+        StackCacheLimits {
+            max_stacks_per_size_class: DEFAULT_MAX_CACHED_STACKS_PER_SIZE_CLASS,
+            max_bytes: DEFAULT_MAX_CACHED_STACK_BYTES,
+        }
+    }
+}
+
+/// Stacks are allocated rounded up to a power of two, so any stack in a
+/// class can be handed to a coroutine asking for a size in that class
+pub fn size_class(stack_size: usize) -> usize {
+    stack_size.next_power_of_two()
+}
+
+/// Stacks of finished coroutines kept by a scheduler for the coroutines it
+/// starts next. A context owns its stack, so stacks are cached along with
+/// the context that ran on them, which is left parked ready to run the next
+/// coroutine body.
+pub struct StackCache {
+    cached_bytes: usize,
+    limits: StackCacheLimits,
+    size_classes: HashMap<usize, Vec<CoroutineStack>>,
+}
+
+impl StackCache {
+    pub fn new(limits: StackCacheLimits) -> StackCache {
+        StackCache {
+            cached_bytes: 0,
+            limits: limits,
+            size_classes: HashMap::new(),
+        }
+    }
+
+    /// A cached stack in the size's class, if there is one
+    pub fn take(&mut self, stack_size: usize) -> Option<CoroutineStack> {
+        let size_class = size_class(stack_size);
+        let maybe_stack = match self.size_classes.get_mut(&size_class) {
+            Some(stacks) => stacks.pop(),
+            None => None,
+        };
+        if maybe_stack.is_some() {
+            self.cached_bytes -= size_class;
+        }
+
+        maybe_stack
+    }
+
+    /// Keeps the finished coroutine's stack for reuse, or frees it if the
+    /// cache is already at its limits
+    pub fn give(&mut self, stack_size: usize, stack: CoroutineStack) {
+        let size_class = size_class(stack_size);
+        if self.cached_bytes + size_class > self.limits.max_bytes {
+            return
+        }
+
+        let stacks = self.size_classes.entry(size_class).or_insert_with(Vec::new);
+        if stacks.len() >= self.limits.max_stacks_per_size_class {
+            return
+        }
+        stacks.push(stack);
+        self.cached_bytes += size_class;
+    }
+}
@@ -0,0 +1,151 @@
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::wake;
+use IoHandle;
+use Result;
+
+/// How many coroutines have arrived at the barrier, and a generation that's
+/// bumped every time the last one arrives and releases the rest
+struct BarrierState {
+    arrived: usize,
+    generation: u64,
+    parked: Vec<BlockedMessage>,
+}
+
+impl BarrierState {
+    fn park(&mut self, blocked_message: BlockedMessage, generation: u64) -> Result<()> {
+        if generation != self.generation {
+            return blocked_message.wake()
+        }
+        self.parked.push(blocked_message);
+
+        Ok(())
+    }
+}
+
+fn lock_state(state: &StdMutex<BarrierState>) -> StdMutexGuard<BarrierState> {
+    state
+        .lock()
+        .expect("Coros internal error: barrier state lock poisoned")
+}
+
+/// Lets a number of coroutines wait until they've all reached the same
+/// point. The barrier can be reused once they've all been released.
+pub struct Barrier {
+    count: usize,
+    state: Arc<StdMutex<BarrierState>>,
+}
+
+impl Barrier {
+    pub fn new(count: usize) -> Barrier {
+        Barrier {
+            count: count,
+            state: Arc::new(StdMutex::new(BarrierState {
+                arrived: 0,
+                generation: 0,
+                parked: Vec::new(),
+            })),
+        }
+    }
+
+    /// Suspends the coroutine until count coroutines have called wait. The
+    /// last one to arrive doesn't suspend, and is the only one that gets
+    /// true back.
+    pub fn wait(&self, coroutine_handle: &mut IoHandle) -> Result<bool> {
+        let (generation, maybe_parked) = {
+            let mut state = lock_state(&self.state);
+            state.arrived += 1;
+            let generation = state.generation;
+            if state.arrived < self.count {
+                (generation, None)
+            } else {
+                state.arrived = 0;
+                state.generation += 1;
+                let parked = mem::replace(&mut state.parked, Vec::new());
+
+                (generation, Some(parked))
+            }
+        };
+
+        if let Some(parked) = maybe_parked {
+            for blocked_message in parked {
+                wake(blocked_message);
+            }
+
+            return Ok(true)
+        }
+
+        coroutine_handle.wait_for(&mut BarrierWaiter {
+            generation: generation,
+            is_released: false,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl panic::RecoverSafe for Barrier {}
+impl panic::RefRecoverSafe for Barrier {}
+
+/// Waits for the generation to move on, on behalf of Barrier::wait
+struct BarrierWaiter {
+    generation: u64,
+    is_released: bool,
+    state: Arc<StdMutex<BarrierState>>,
+}
+
+impl Waitable for BarrierWaiter {
+    type Item = bool;
+
+    fn try_take(&mut self) -> Option<Result<bool>> {
+        let state = lock_state(&self.state);
+        if state.generation == self.generation {
+            return None
+        }
+        self.is_released = true;
+
+        Some(Ok(false))
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.state.clone();
+        let generation = self.generation;
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message, generation)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        lock_state(&self.state)
+            .parked
+            .retain(|blocked_message| blocked_message.wait_id != wait_id);
+    }
+}
+
+/// Leaves the barrier once the wait is over. A coroutine that stopped
+/// waiting before the barrier released, because it timed out or was
+/// cancelled, no longer counts as arrived, so the barrier still waits for
+/// count coroutines that are actually waiting.
+impl Drop for BarrierWaiter {
+    fn drop(&mut self) {
+        if self.is_released {
+            return
+        }
+        if let Ok(mut state) = self.state.lock() {
+            if state.generation == self.generation {
+                state.arrived -= 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::{
+    HashSet,
+    VecDeque,
+};
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::mutex::{
+    MutexGuard,
+    guard_mutex,
+};
+use sync::wake;
+use IoHandle;
+use Result;
+
+/// A coroutine waiting on the condvar, and the BlockedMessage to wake it
+/// with once it's parked
+struct WaitingCoroutine {
+    id: u64,
+    maybe_parked: Option<BlockedMessage>,
+}
+
+/// Coroutines join the waiting queue before they unlock the mutex, so a
+/// notification sent between unlocking and parking isn't missed. Notified
+/// coroutines move from the queue to the notified set until they wake.
+struct CondvarState {
+    next_id: u64,
+    notified: HashSet<u64>,
+    waiting: VecDeque<WaitingCoroutine>,
+}
+
+impl CondvarState {
+    fn park(&mut self, blocked_message: BlockedMessage, id: u64) -> Result<()> {
+        if self.notified.contains(&id) {
+            return blocked_message.wake()
+        }
+        for waiting_coroutine in self.waiting.iter_mut() {
+            if waiting_coroutine.id == id {
+                waiting_coroutine.maybe_parked = Some(blocked_message);
+                break
+            }
+        }
+
+        Ok(())
+    }
+
+    fn notify(&mut self, waiting_coroutine: WaitingCoroutine) {
+        self.notified.insert(waiting_coroutine.id);
+        if let Some(blocked_message) = waiting_coroutine.maybe_parked {
+            wake(blocked_message);
+        }
+    }
+
+    fn notify_one(&mut self) {
+        if let Some(waiting_coroutine) = self.waiting.pop_front() {
+            self.notify(waiting_coroutine);
+        }
+    }
+}
+
+fn lock_state(state: &StdMutex<CondvarState>) -> StdMutexGuard<CondvarState> {
+    state
+        .lock()
+        .expect("Coros internal error: condvar state lock poisoned")
+}
+
+/// A condition variable for coroutines, used with a coros::sync::Mutex.
+/// Waiting suspends the coroutine rather than blocking the thread running
+/// it, notifications can be sent from coroutines or threads.
+pub struct Condvar {
+    state: Arc<StdMutex<CondvarState>>,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            state: Arc::new(StdMutex::new(CondvarState {
+                next_id: 0,
+                notified: HashSet::new(),
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Unlocks the mutex and suspends the coroutine until it's notified,
+    /// then locks the mutex again. Like any condvar it should be waited on
+    /// in a loop that checks the condition. If the coroutine times out the
+    /// mutex is left unlocked.
+    pub fn wait<'a, T>(
+        &self,
+        coroutine_handle: &mut IoHandle,
+        guard: MutexGuard<'a, T>,
+    ) -> Result<MutexGuard<'a, T>> {
+        let mutex = guard_mutex(&guard);
+        let mut waiter = self.waiter();
+        drop(guard);
+
+        try!(coroutine_handle.wait_for(&mut waiter));
+        drop(waiter);
+
+        mutex.lock(coroutine_handle)
+    }
+
+    /// Wakes the coroutine that's been waiting longest, if any are
+    pub fn notify_one(&self) {
+        lock_state(&self.state).notify_one();
+    }
+
+    /// Wakes every waiting coroutine
+    pub fn notify_all(&self) {
+        let mut state = lock_state(&self.state);
+        let waiting = mem::replace(&mut state.waiting, VecDeque::new());
+        for waiting_coroutine in waiting {
+            state.notify(waiting_coroutine);
+        }
+    }
+
+    fn waiter(&self) -> CondvarWaiter {
+        let mut state = lock_state(&self.state);
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back(WaitingCoroutine {
+            id: id,
+            maybe_parked: None,
+        });
+
+        CondvarWaiter {
+            id: id,
+            is_woken: false,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl panic::RecoverSafe for Condvar {}
+impl panic::RefRecoverSafe for Condvar {}
+
+/// Waits for a notification on behalf of Condvar::wait
+struct CondvarWaiter {
+    id: u64,
+    is_woken: bool,
+    state: Arc<StdMutex<CondvarState>>,
+}
+
+impl Waitable for CondvarWaiter {
+    type Item = ();
+
+    fn try_take(&mut self) -> Option<Result<()>> {
+        let is_notified = lock_state(&self.state).notified.contains(&self.id);
+        if !is_notified {
+            return None
+        }
+        self.is_woken = true;
+
+        Some(Ok(()))
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.state.clone();
+        let id = self.id;
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message, id)
+        })
+    }
+
+    /// Coroutines that are still waiting are parked again with a new
+    /// BlockedMessage, so the old one is cleared here
+    fn finish_wait(&self, _: usize, _: bool) {
+        let mut state = lock_state(&self.state);
+        for waiting_coroutine in state.waiting.iter_mut() {
+            if waiting_coroutine.id == self.id {
+                waiting_coroutine.maybe_parked = None;
+            }
+        }
+    }
+}
+
+/// Leaves the condvar once the wait is over. A coroutine that was notified
+/// but stopped waiting before it woke, because it timed out or was
+/// cancelled, passes the notification on.
+impl Drop for CondvarWaiter {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            let id = self.id;
+            state.waiting.retain(|waiting_coroutine| waiting_coroutine.id != id);
+            let was_notified = state.notified.remove(&id);
+            if was_notified && !self.is_woken {
+                state.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::VecDeque;
+
+use coroutine::channel::BlockedMessage;
+
+mod barrier;
+mod condvar;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+mod wait_group;
+
+pub use self::barrier::Barrier;
+pub use self::condvar::Condvar;
+pub use self::mutex::{
+    Mutex,
+    MutexGuard,
+};
+pub use self::notify::Notify;
+pub use self::rwlock::{
+    RwLock,
+    RwLockReadGuard,
+    RwLockWriteGuard,
+};
+pub use self::semaphore::{
+    Semaphore,
+    SemaphorePermit,
+};
+pub use self::wait_group::WaitGroup;
+
+fn remove_parked(parked: &mut VecDeque<BlockedMessage>, wait_id: usize) -> bool {
+    let parked_count = parked.len();
+    parked.retain(|blocked_message| blocked_message.wait_id != wait_id);
+
+    parked.len() != parked_count
+}
+
+/// Wakes parked coroutines through the notify handler of whichever scheduler
+/// they're parked on
+fn wake(blocked_message: BlockedMessage) {
+    if let Err(err) = blocked_message.wake() {
+        error!("Error waking coroutine parked on sync primitive: {:?}", err);
+    }
+}
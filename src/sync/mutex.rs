@@ -0,0 +1,206 @@
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::{
+    remove_parked,
+    wake,
+};
+use IoHandle;
+use Result;
+
+/// Whether the mutex is locked, and the coroutines parked waiting for it.
+/// Unlocking wakes the first parked coroutine, which then competes for the
+/// lock like any other.
+struct MutexState {
+    is_locked: bool,
+    parked: VecDeque<BlockedMessage>,
+}
+
+impl MutexState {
+    fn park(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if !self.is_locked {
+            return blocked_message.wake()
+        }
+        self.parked.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    /// A coroutine woken for the lock that left without taking it passes the
+    /// wakeup on, so the lock isn't left free while others are parked
+    fn finish_wait(&mut self, wait_id: usize, locked: bool) {
+        let was_parked = remove_parked(&mut self.parked, wait_id);
+        if was_parked || locked || self.is_locked {
+            return
+        }
+
+        if let Some(blocked_message) = self.parked.pop_front() {
+            wake(blocked_message);
+        }
+    }
+}
+
+fn lock_state(state: &StdMutex<MutexState>) -> StdMutexGuard<MutexState> {
+    state
+        .lock()
+        .expect("Coros internal error: mutex state lock poisoned")
+}
+
+/// A mutex for sharing data between coroutines. Waiting for the lock
+/// suspends the coroutine rather than blocking the thread running it, so it
+/// can be held across IoHandle calls that suspend.
+pub struct Mutex<T> {
+    state: Arc<StdMutex<MutexState>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            state: Arc::new(StdMutex::new(MutexState {
+                is_locked: false,
+                parked: VecDeque::new(),
+            })),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Suspends the coroutine until the lock is free
+    pub fn lock(&self, coroutine_handle: &mut IoHandle) -> Result<MutexGuard<T>> {
+        coroutine_handle.wait_for(&mut Locker {
+            mutex: self,
+        })
+    }
+
+    /// Takes the lock if it's free, without blocking. Can be called from
+    /// coroutines or threads.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = lock_state(&self.state);
+        if state.is_locked {
+            return None
+        }
+        state.is_locked = true;
+
+        Some(MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    pub fn into_inner(self) -> T {
+        unsafe { self.value.into_inner() }
+    }
+}
+
+impl<T> panic::RecoverSafe for Mutex<T> {}
+impl<T> panic::RefRecoverSafe for Mutex<T> {}
+
+/// Waits for a mutex on behalf of Mutex::lock
+struct Locker<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Waitable for Locker<'a, T> {
+    type Item = MutexGuard<'a, T>;
+
+    fn try_take(&mut self) -> Option<Result<MutexGuard<'a, T>>> {
+        self.mutex.try_lock().map(Ok)
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.mutex.state.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, locked: bool) {
+        lock_state(&self.mutex.state).finish_wait(wait_id, locked);
+    }
+}
+
+/// Unlocks the mutex when it's dropped, including while the coroutine
+/// holding it unwinds.
+///
+/// A shared guard hands out &T, so the guard is only Sync if T is, even
+/// though the mutex itself only needs T to be Send. A guard over a Cell
+/// can't be shared with scoped coroutines:
+///
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use coros::sync::Mutex;
+///
+/// fn assert_sync<T: Sync>(_: &T) {}
+///
+/// let mutex = Mutex::new(Cell::new(0));
+/// let guard = mutex.try_lock().unwrap();
+/// assert_sync(&guard);
+/// ```
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+    // Keeps the guard from inheriting Send and Sync from the mutex
+    _marker: PhantomData<&'a UnsafeCell<T>>,
+}
+
+unsafe impl<'a, T: Send> Send for MutexGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for MutexGuard<'a, T> {}
+
+/// The mutex a guard has locked, for Condvar to lock it again after waiting
+pub fn guard_mutex<'a, T>(guard: &MutexGuard<'a, T>) -> &'a Mutex<T> {
+    guard.mutex
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let maybe_parked = match self.mutex.state.lock() {
+            Ok(mut state) => {
+                state.is_locked = false;
+
+                state.parked.pop_front()
+            },
+            Err(_) => return,
+        };
+
+        if let Some(blocked_message) = maybe_parked {
+            wake(blocked_message);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{
+    HashSet,
+    VecDeque,
+};
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::wake;
+use IoHandle;
+use Result;
+
+/// A coroutine parked waiting for a notification
+struct ParkedWaiter {
+    blocked_message: BlockedMessage,
+    id: u64,
+}
+
+/// notify_one hands its notification straight to the coroutine that's been
+/// parked longest, moving it to the notified set until it wakes. With
+/// nobody parked the notification is kept as a permit for the next waiter.
+/// notify_all bumps the generation, releasing every coroutine that started
+/// waiting before it.
+struct NotifyState {
+    generation: u64,
+    has_permit: bool,
+    next_id: u64,
+    notified: HashSet<u64>,
+    parked: VecDeque<ParkedWaiter>,
+}
+
+impl NotifyState {
+    fn park(&mut self, blocked_message: BlockedMessage, id: u64, generation: u64) -> Result<()> {
+        if self.has_permit || self.notified.contains(&id) || generation != self.generation {
+            return blocked_message.wake()
+        }
+        self.parked.push_back(ParkedWaiter {
+            blocked_message: blocked_message,
+            id: id,
+        });
+
+        Ok(())
+    }
+
+    fn notify_one(&mut self) {
+        match self.parked.pop_front() {
+            Some(parked_waiter) => {
+                self.notified.insert(parked_waiter.id);
+                wake(parked_waiter.blocked_message);
+            },
+            None => self.has_permit = true,
+        }
+    }
+
+    /// A coroutine woken by a timeout or cancellation is still parked
+    fn finish_wait(&mut self, wait_id: usize) {
+        self.parked.retain(|parked_waiter| parked_waiter.blocked_message.wait_id != wait_id);
+    }
+
+    fn leave(&mut self, id: u64) {
+        self.parked.retain(|parked_waiter| parked_waiter.id != id);
+        if self.notified.remove(&id) {
+            self.notify_one();
+        }
+    }
+}
+
+fn lock_state(state: &StdMutex<NotifyState>) -> StdMutexGuard<NotifyState> {
+    state
+        .lock()
+        .expect("Coros internal error: notify state lock poisoned")
+}
+
+/// Wakes coroutines waiting for something to happen, without passing them
+/// any data
+pub struct Notify {
+    state: Arc<StdMutex<NotifyState>>,
+}
+
+impl Notify {
+    pub fn new() -> Notify {
+        Notify {
+            state: Arc::new(StdMutex::new(NotifyState {
+                generation: 0,
+                has_permit: false,
+                next_id: 0,
+                notified: HashSet::new(),
+                parked: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Wakes one waiting coroutine. If none are waiting the next coroutine
+    /// to wait returns straight away, notifications don't accumulate past
+    /// that one.
+    pub fn notify_one(&self) {
+        lock_state(&self.state).notify_one();
+    }
+
+    /// Wakes every coroutine that's currently waiting. Coroutines that start
+    /// waiting afterwards aren't affected.
+    pub fn notify_all(&self) {
+        let parked = {
+            let mut state = lock_state(&self.state);
+            state.generation += 1;
+
+            mem::replace(&mut state.parked, VecDeque::new())
+        };
+
+        for parked_waiter in parked {
+            wake(parked_waiter.blocked_message);
+        }
+    }
+
+    /// Suspends the coroutine until it's notified
+    pub fn notified(&self, coroutine_handle: &mut IoHandle) -> Result<()> {
+        let (generation, id) = {
+            let mut state = lock_state(&self.state);
+            let id = state.next_id;
+            state.next_id += 1;
+
+            (state.generation, id)
+        };
+
+        coroutine_handle.wait_for(&mut NotifyWaiter {
+            generation: generation,
+            id: id,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl panic::RecoverSafe for Notify {}
+impl panic::RefRecoverSafe for Notify {}
+
+/// Waits for a notification on behalf of Notify::notified
+struct NotifyWaiter {
+    generation: u64,
+    id: u64,
+    state: Arc<StdMutex<NotifyState>>,
+}
+
+impl Waitable for NotifyWaiter {
+    type Item = ();
+
+    fn try_take(&mut self) -> Option<Result<()>> {
+        let mut state = lock_state(&self.state);
+        if state.notified.remove(&self.id) || state.generation != self.generation {
+            return Some(Ok(()))
+        }
+        if !state.has_permit {
+            return None
+        }
+        state.has_permit = false;
+
+        Some(Ok(()))
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.state.clone();
+        let generation = self.generation;
+        let id = self.id;
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message, id, generation)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        lock_state(&self.state).finish_wait(wait_id);
+    }
+}
+
+/// Leaves the notify once the wait is over. A coroutine that was notified
+/// but stopped waiting before it woke, because it timed out or was
+/// cancelled, passes the notification on to the next waiter, or leaves it
+/// as the permit if nobody else is waiting.
+impl Drop for NotifyWaiter {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.leave(self.id);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::{
+    remove_parked,
+    wake,
+};
+use IoHandle;
+use Result;
+
+/// Who holds the lock, and the coroutines parked waiting for it. Parked
+/// writers hold off new readers, so a steady stream of readers can't starve
+/// them.
+struct RwLockState {
+    is_writing: bool,
+    parked_readers: VecDeque<BlockedMessage>,
+    parked_writers: VecDeque<BlockedMessage>,
+    reader_count: usize,
+}
+
+impl RwLockState {
+    fn can_read(&self) -> bool {
+        !self.is_writing && self.parked_writers.is_empty()
+    }
+
+    fn can_write(&self) -> bool {
+        !self.is_writing && self.reader_count == 0
+    }
+
+    fn park_reader(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.can_read() {
+            return blocked_message.wake()
+        }
+        self.parked_readers.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    fn park_writer(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.can_write() {
+            return blocked_message.wake()
+        }
+        self.parked_writers.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    /// Wakes the first parked writer if the lock is free, otherwise every
+    /// parked reader if they're allowed in
+    fn wake_next(&mut self) {
+        if self.can_write() {
+            if let Some(blocked_message) = self.parked_writers.pop_front() {
+                wake(blocked_message);
+                return
+            }
+        }
+        if self.can_read() {
+            for blocked_message in mem::replace(&mut self.parked_readers, VecDeque::new()) {
+                wake(blocked_message);
+            }
+        }
+    }
+
+    /// A coroutine woken for the lock that left without taking it passes the
+    /// wakeup on
+    fn finish_wait(&mut self, wait_id: usize, locked: bool) {
+        let was_parked_reader = remove_parked(&mut self.parked_readers, wait_id);
+        let was_parked_writer = remove_parked(&mut self.parked_writers, wait_id);
+        if was_parked_reader || was_parked_writer || locked {
+            return
+        }
+
+        self.wake_next();
+    }
+}
+
+fn lock_state(state: &StdMutex<RwLockState>) -> StdMutexGuard<RwLockState> {
+    state
+        .lock()
+        .expect("Coros internal error: rwlock state lock poisoned")
+}
+
+/// A reader-writer lock for sharing data between coroutines. Waiting for
+/// the lock suspends the coroutine rather than blocking the thread running
+/// it.
+pub struct RwLock<T> {
+    state: Arc<StdMutex<RwLockState>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: Arc::new(StdMutex::new(RwLockState {
+                is_writing: false,
+                parked_readers: VecDeque::new(),
+                parked_writers: VecDeque::new(),
+                reader_count: 0,
+            })),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Suspends the coroutine until there's no writer holding or waiting for
+    /// the lock
+    pub fn read(&self, coroutine_handle: &mut IoHandle) -> Result<RwLockReadGuard<T>> {
+        coroutine_handle.wait_for(&mut Reader {
+            rwlock: self,
+        })
+    }
+
+    /// Suspends the coroutine until no other coroutine holds the lock
+    pub fn write(&self, coroutine_handle: &mut IoHandle) -> Result<RwLockWriteGuard<T>> {
+        coroutine_handle.wait_for(&mut Writer {
+            rwlock: self,
+        })
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let mut state = lock_state(&self.state);
+        if !state.can_read() {
+            return None
+        }
+        state.reader_count += 1;
+
+        Some(RwLockReadGuard {
+            rwlock: self,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        let mut state = lock_state(&self.state);
+        if !state.can_write() {
+            return None
+        }
+        state.is_writing = true;
+
+        Some(RwLockWriteGuard {
+            rwlock: self,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    pub fn into_inner(self) -> T {
+        unsafe { self.value.into_inner() }
+    }
+}
+
+impl<T> panic::RecoverSafe for RwLock<T> {}
+impl<T> panic::RefRecoverSafe for RwLock<T> {}
+
+/// Waits for a read lock on behalf of RwLock::read
+struct Reader<'a, T: 'a> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<'a, T> Waitable for Reader<'a, T> {
+    type Item = RwLockReadGuard<'a, T>;
+
+    fn try_take(&mut self) -> Option<Result<RwLockReadGuard<'a, T>>> {
+        self.rwlock.try_read().map(Ok)
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.rwlock.state.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park_reader(blocked_message)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, locked: bool) {
+        lock_state(&self.rwlock.state).finish_wait(wait_id, locked);
+    }
+}
+
+/// Waits for the write lock on behalf of RwLock::write
+struct Writer<'a, T: 'a> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<'a, T> Waitable for Writer<'a, T> {
+    type Item = RwLockWriteGuard<'a, T>;
+
+    fn try_take(&mut self) -> Option<Result<RwLockWriteGuard<'a, T>>> {
+        self.rwlock.try_write().map(Ok)
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.rwlock.state.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park_writer(blocked_message)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, locked: bool) {
+        lock_state(&self.rwlock.state).finish_wait(wait_id, locked);
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: 'a> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+/// The last reader to go wakes a parked writer
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.rwlock.state.lock() {
+            state.reader_count -= 1;
+            if state.reader_count == 0 {
+                state.wake_next();
+            }
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.rwlock.state.lock() {
+            state.is_writing = false;
+            state.wake_next();
+        }
+    }
+}
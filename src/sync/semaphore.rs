@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::{
+    remove_parked,
+    wake,
+};
+use IoHandle;
+use Result;
+
+struct SemaphoreState {
+    parked: VecDeque<BlockedMessage>,
+    permits: usize,
+}
+
+impl SemaphoreState {
+    fn park(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.permits > 0 {
+            return blocked_message.wake()
+        }
+        self.parked.push_back(blocked_message);
+
+        Ok(())
+    }
+
+    /// Wakes a parked coroutine for each of the permits
+    fn wake_parked(&mut self, permits: usize) {
+        for _ in 0..permits {
+            match self.parked.pop_front() {
+                Some(blocked_message) => wake(blocked_message),
+                None => return,
+            }
+        }
+    }
+
+    /// A coroutine woken for a permit that left without taking it passes the
+    /// wakeup on
+    fn finish_wait(&mut self, wait_id: usize, acquired: bool) {
+        let was_parked = remove_parked(&mut self.parked, wait_id);
+        if was_parked || acquired || self.permits == 0 {
+            return
+        }
+
+        self.wake_parked(1);
+    }
+}
+
+fn lock_state(state: &StdMutex<SemaphoreState>) -> StdMutexGuard<SemaphoreState> {
+    state
+        .lock()
+        .expect("Coros internal error: semaphore state lock poisoned")
+}
+
+/// A counting semaphore, for limiting how many coroutines do something at
+/// once. Coroutines waiting for a permit are suspended rather than blocking
+/// the thread running them.
+pub struct Semaphore {
+    state: Arc<StdMutex<SemaphoreState>>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            state: Arc::new(StdMutex::new(SemaphoreState {
+                parked: VecDeque::new(),
+                permits: permits,
+            })),
+        }
+    }
+
+    /// Suspends the coroutine until a permit is available. The permit is
+    /// returned when the SemaphorePermit is dropped.
+    pub fn acquire(&self, coroutine_handle: &mut IoHandle) -> Result<SemaphorePermit> {
+        coroutine_handle.wait_for(&mut Acquirer {
+            semaphore: self,
+        })
+    }
+
+    /// Takes a permit if one is available, without blocking. Can be called
+    /// from coroutines or threads.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        let mut state = lock_state(&self.state);
+        if state.permits == 0 {
+            return None
+        }
+        state.permits -= 1;
+
+        Some(SemaphorePermit {
+            semaphore: self,
+        })
+    }
+
+    /// Adds permits, waking a parked coroutine for each of them
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = lock_state(&self.state);
+        state.permits += permits;
+        state.wake_parked(permits);
+    }
+
+    pub fn available_permits(&self) -> usize {
+        lock_state(&self.state).permits
+    }
+}
+
+impl panic::RecoverSafe for Semaphore {}
+impl panic::RefRecoverSafe for Semaphore {}
+
+/// Waits for a permit on behalf of Semaphore::acquire
+struct Acquirer<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Waitable for Acquirer<'a> {
+    type Item = SemaphorePermit<'a>;
+
+    fn try_take(&mut self) -> Option<Result<SemaphorePermit<'a>>> {
+        self.semaphore.try_acquire().map(Ok)
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.semaphore.state.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, acquired: bool) {
+        lock_state(&self.semaphore.state).finish_wait(wait_id, acquired);
+    }
+}
+
+/// Returns its permit to the semaphore when it's dropped, including while
+/// the coroutine holding it unwinds
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.semaphore.state.lock() {
+            state.permits += 1;
+            state.wake_parked(1);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::mem;
+use std::panic;
+use std::sync::{
+    Arc,
+    Mutex as StdMutex,
+    MutexGuard as StdMutexGuard,
+};
+
+use coroutine::channel::BlockedMessage;
+use coroutine::waiter::{
+    ParkCallback,
+    Waitable,
+};
+use sync::wake;
+use IoHandle;
+use Result;
+
+struct WaitGroupState {
+    count: usize,
+    parked: Vec<BlockedMessage>,
+}
+
+impl WaitGroupState {
+    fn park(&mut self, blocked_message: BlockedMessage) -> Result<()> {
+        if self.count == 0 {
+            return blocked_message.wake()
+        }
+        self.parked.push(blocked_message);
+
+        Ok(())
+    }
+}
+
+fn lock_state(state: &StdMutex<WaitGroupState>) -> StdMutexGuard<WaitGroupState> {
+    state
+        .lock()
+        .expect("Coros internal error: wait group state lock poisoned")
+}
+
+/// Counts outstanding work, such as spawned child coroutines, so a coroutine
+/// can wait for all of it to be done. Clones share the same count.
+#[derive(Clone)]
+pub struct WaitGroup {
+    state: Arc<StdMutex<WaitGroupState>>,
+}
+
+impl WaitGroup {
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            state: Arc::new(StdMutex::new(WaitGroupState {
+                count: 0,
+                parked: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn add(&self, count: usize) {
+        lock_state(&self.state).count += count;
+    }
+
+    /// Marks one piece of work done, waking every parked coroutine once the
+    /// count reaches zero. Panics if more work is done than was added.
+    pub fn done(&self) {
+        let parked = {
+            let mut state = lock_state(&self.state);
+            assert!(state.count > 0, "WaitGroup::done called more times than was added");
+            state.count -= 1;
+            if state.count > 0 {
+                return
+            }
+
+            mem::replace(&mut state.parked, Vec::new())
+        };
+
+        for blocked_message in parked {
+            wake(blocked_message);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        lock_state(&self.state).count
+    }
+
+    /// Suspends the coroutine until the count is zero
+    pub fn wait(&self, coroutine_handle: &mut IoHandle) -> Result<()> {
+        coroutine_handle.wait_for(&mut WaitGroupWaiter {
+            wait_group: self,
+        })
+    }
+}
+
+impl panic::RecoverSafe for WaitGroup {}
+impl panic::RefRecoverSafe for WaitGroup {}
+
+/// Waits for the count to reach zero on behalf of WaitGroup::wait
+struct WaitGroupWaiter<'a> {
+    wait_group: &'a WaitGroup,
+}
+
+impl<'a> Waitable for WaitGroupWaiter<'a> {
+    type Item = ();
+
+    fn try_take(&mut self) -> Option<Result<()>> {
+        if self.wait_group.count() > 0 {
+            return None
+        }
+
+        Some(Ok(()))
+    }
+
+    fn park_callback(&self) -> ParkCallback {
+        let state = self.wait_group.state.clone();
+
+        Box::new(move |blocked_message: BlockedMessage| -> Result<()> {
+            lock_state(&state).park(blocked_message)
+        })
+    }
+
+    fn finish_wait(&self, wait_id: usize, _: bool) {
+        lock_state(&self.wait_group.state)
+            .parked
+            .retain(|blocked_message| blocked_message.wait_id != wait_id);
+    }
+}
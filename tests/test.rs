@@ -1,11 +1,19 @@
 #![feature(recover)]
 extern crate bytes;
+extern crate libc;
 extern crate mio;
 extern crate time;
 
 extern crate coros;
 
-use std::sync::Mutex;
+use std::env;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::sync::{
+    Arc,
+    Mutex,
+};
 use std::time::Duration as StdDuration;
 
 use bytes::SliceBuf;
@@ -17,8 +25,15 @@ use time::{
 
 use coros::{
     channel,
+    CorosError,
+    Generator,
+    GeneratorHandle,
     IoHandle,
     Pool,
+    PoolBuilder,
+    Selected,
+    WaitSpec,
+    sync,
 };
 
 const STACK_SIZE: usize = 2 * 1024 * 1024;
@@ -490,3 +505,1317 @@ fn test_panics_are_isolated_to_coroutines() {
     assert!(guard0.join().unwrap().is_err());
     assert_eq!(1, guard1.join().unwrap().unwrap());
 }
+
+#[test]
+fn test_yield_now() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let is_done = Arc::new(Mutex::new(false));
+    let spinner_is_done = is_done.clone();
+
+    let mut setter_guard = pool.spawn(
+        move |_: IoHandle| {
+            *is_done.lock().unwrap() = true;
+            1
+        },
+        STACK_SIZE,
+    ).unwrap();
+    let mut spinner_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let mut yield_count = 0;
+            while !*spinner_is_done.lock().unwrap() {
+                coroutine_handle.yield_now().unwrap();
+                yield_count += 1;
+            }
+            yield_count
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(spinner_guard.join().unwrap().unwrap() > 0);
+    assert_eq!(1, setter_guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_spawning_from_a_coroutine() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            (0..4)
+                .map(|i| coroutine_handle.spawn(move |_| { i * 2 }, STACK_SIZE).unwrap())
+                .collect::<Vec<_>>()
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    let mut child_guards = guard.join().unwrap().unwrap();
+    for (i, child_guard) in child_guards.iter_mut().enumerate() {
+        assert_eq!(i * 2, child_guard.join().unwrap().unwrap());
+    }
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_joining_a_coroutine_from_a_coroutine() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let mut child_guard = coroutine_handle.spawn(
+                |mut child_handle: IoHandle| {
+                    child_handle.sleep(StdDuration::from_millis(50)).unwrap();
+                    2
+                },
+                STACK_SIZE,
+            ).unwrap();
+
+            coroutine_handle.join(&mut child_guard).unwrap().unwrap() + 1
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(3, guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_joining_a_finished_coroutine_from_a_coroutine() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let mut child_guard = coroutine_handle.spawn(|_| { 2 }, STACK_SIZE).unwrap();
+            coroutine_handle.yield_now().unwrap();
+
+            let result = coroutine_handle.join(&mut child_guard).unwrap().unwrap();
+            assert!(coroutine_handle.join(&mut child_guard).is_err());
+
+            result
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(2, guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_spawner_is_shareable_between_threads() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    pool.start().unwrap();
+
+    let producers: Vec<_> = (0..4).map(|i| {
+        let spawner = pool.spawner();
+        std::thread::spawn(move || {
+            spawner.spawn(move |_| { i }, STACK_SIZE).unwrap().join().unwrap().unwrap()
+        })
+    }).collect();
+
+    for (i, producer) in producers.into_iter().enumerate() {
+        assert_eq!(i, producer.join().unwrap());
+    }
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_spawner_survives_pool_restarts() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let spawner = pool.spawner();
+
+    pool.start().unwrap();
+    assert_eq!(1, spawner.spawn(|_| { 1 }, STACK_SIZE).unwrap().join().unwrap().unwrap());
+    pool.stop().unwrap();
+
+    let mut guard = spawner.spawn(|_| { 2 }, STACK_SIZE).unwrap();
+    pool.start().unwrap();
+    assert_eq!(2, guard.join().unwrap().unwrap());
+    assert!(spawner.spawn_with_thread_index(|_| { 3 }, STACK_SIZE, 1).is_err());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_pool_builder() {
+    let started_count = Arc::new(Mutex::new(0));
+    let stopped_count = Arc::new(Mutex::new(0));
+    let hook_started_count = started_count.clone();
+    let hook_stopped_count = stopped_count.clone();
+
+    let mut pool = PoolBuilder::new()
+        .name("built".to_string())
+        .thread_count(2)
+        .stack_size(STACK_SIZE)
+        .blocked_coroutine_capacity(16)
+        .on_thread_start(move || { *hook_started_count.lock().unwrap() += 1; })
+        .on_thread_stop(move || { *hook_stopped_count.lock().unwrap() += 1; })
+        .build()
+        .unwrap();
+    let mut guard = pool.spawn_with_default_stack(|_| {
+        std::thread::current().name().unwrap().to_string()
+    }).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap().starts_with("built-"));
+    pool.stop().unwrap();
+
+    assert_eq!(2, *started_count.lock().unwrap());
+    assert_eq!(2, *stopped_count.lock().unwrap());
+}
+
+#[test]
+fn test_pool_builder_defaults_to_a_thread_per_cpu() {
+    let mut pool = PoolBuilder::new().build().unwrap();
+    let mut guard = pool.spawn_with_default_stack(|_| { 1 }).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(1, guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+struct DropFlag(Arc<Mutex<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = true;
+    }
+}
+
+#[test]
+fn test_aborting_a_coroutine_blocked_on_io() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, _writer) = unix::pipe().unwrap();
+    let was_dropped = Arc::new(Mutex::new(false));
+    let coroutine_was_dropped = was_dropped.clone();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let _drop_flag = DropFlag(coroutine_was_dropped);
+            coroutine_handle.register(
+                &reader,
+                EventSet::readable(),
+                PollOpt::level(),
+            ).unwrap();
+            1
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    std::thread::sleep(StdDuration::from_millis(50));
+    guard.abort().unwrap();
+
+    match guard.join().unwrap() {
+        Err(CorosError::CoroutineCancelled) => (),
+        result => panic!("Unexpected coroutine result {:?}", result),
+    }
+    assert!(*was_dropped.lock().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_aborting_a_sleeping_coroutine_lets_the_pool_stop() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.sleep(StdDuration::from_secs(60 * 60)).unwrap();
+            1
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    std::thread::sleep(StdDuration::from_millis(50));
+    guard.abort().unwrap();
+    pool.stop().unwrap();
+
+    assert!(guard.join().unwrap().is_err());
+}
+
+#[test]
+fn test_register_timeout() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, _writer) = unix::pipe().unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let start_time = now();
+            let result = coroutine_handle.register_timeout(
+                &reader,
+                EventSet::readable(),
+                PollOpt::level(),
+                StdDuration::from_millis(200),
+            );
+            assert!((now() - start_time) >= Duration::milliseconds(100));
+
+            match result {
+                Err(CorosError::TimedOut) => true,
+                _ => false,
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_recv_timeout() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (_sender, receiver) = channel::new::<u8>();
+    let receiver_mutex = Mutex::new(receiver);
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let receiver_guard = receiver_mutex.lock().unwrap();
+            match coroutine_handle.recv_timeout(&receiver_guard, StdDuration::from_millis(200)) {
+                Err(CorosError::TimedOut) => true,
+                _ => false,
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_with_deadline() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let short_sleep_result = coroutine_handle.with_deadline(
+                StdDuration::from_millis(1000),
+                |coroutine_handle| coroutine_handle.sleep(StdDuration::from_millis(100)),
+            );
+            assert!(short_sleep_result.is_ok());
+
+            let start_time = now();
+            let long_sleep_result = coroutine_handle.with_deadline(
+                StdDuration::from_millis(200),
+                |coroutine_handle| coroutine_handle.sleep(StdDuration::from_millis(60 * 1000)),
+            );
+            assert!((now() - start_time) < Duration::milliseconds(5000));
+
+            match long_sleep_result {
+                Err(CorosError::TimedOut) => true,
+                _ => false,
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_readiness_arriving_while_blocked_on_something_else_is_kept() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, mut writer) = unix::pipe().unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            // Waiting on a registration doesn't rearm it, so the edge that
+            // arrives during the sleep is only seen if it was kept
+            let mut registration = coroutine_handle.register_source(reader).unwrap();
+            coroutine_handle.sleep(StdDuration::from_millis(500)).unwrap();
+
+            let start_time = now();
+            let result_eventset = registration.wait_readable(&mut coroutine_handle).unwrap();
+            assert!((now() - start_time) < Duration::milliseconds(100));
+            assert!(result_eventset.is_readable());
+
+            let mut result_buf = Vec::<u8>::new();
+            registration.get_mut().try_read_buf(&mut result_buf).unwrap();
+
+            std::str::from_utf8(&result_buf).unwrap().to_string()
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    writer.try_write_buf(&mut SliceBuf::wrap("ping".as_bytes())).unwrap();
+    std::thread::sleep(StdDuration::from_millis(200));
+    writer.try_write_buf(&mut SliceBuf::wrap("pong".as_bytes())).unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), "pingpong");
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_registration_can_be_waited_on_repeatedly() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, writer) = unix::pipe().unwrap();
+    let writer_mutex = Arc::new(Mutex::new(writer));
+    let coroutine_writer_mutex = writer_mutex.clone();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let mut registration = coroutine_handle.register_source(reader).unwrap();
+            let mut result_buf = Vec::<u8>::new();
+
+            for message in ["ping", "pong"].iter() {
+                coroutine_writer_mutex
+                    .lock()
+                    .unwrap()
+                    .try_write_buf(&mut SliceBuf::wrap(message.as_bytes()))
+                    .unwrap();
+                let result_eventset = registration.wait_readable(&mut coroutine_handle).unwrap();
+                assert!(result_eventset.is_readable());
+                registration.get_mut().try_read_buf(&mut result_buf).unwrap();
+            }
+
+            std::str::from_utf8(&result_buf).unwrap().to_string()
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), "pingpong");
+    pool.stop().unwrap();
+}
+
+/// Shares a pipe between registrations, so that dropping one doesn't close
+/// the pipe
+struct SharedPipeReader(Arc<unix::PipeReader>);
+
+impl Evented for SharedPipeReader {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+#[test]
+fn test_dropped_registration_deregisters_its_source() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, mut writer) = unix::pipe().unwrap();
+    let reader = Arc::new(reader);
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let registration = coroutine_handle
+                .register_source(SharedPipeReader(reader.clone()))
+                .unwrap();
+            drop(registration);
+
+            // Registering a source that's still registered fails
+            let registration = coroutine_handle
+                .register_source(SharedPipeReader(reader.clone()))
+                .unwrap();
+            writer.try_write_buf(&mut SliceBuf::wrap("ping".as_bytes())).unwrap();
+            registration.wait_readable(&mut coroutine_handle).unwrap().is_readable()
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_select() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (reader, _writer) = unix::pipe().unwrap();
+    let (sender, receiver) = channel::new::<u8>();
+    let receiver_mutex = Mutex::new(receiver);
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let registration = coroutine_handle.register_source(reader).unwrap();
+            let receiver_guard = receiver_mutex.lock().unwrap();
+
+            let received = coroutine_handle.select(&[
+                WaitSpec::readable(&registration),
+                WaitSpec::recv(&receiver_guard),
+                WaitSpec::timer(StdDuration::from_millis(60 * 1000)),
+            ]).unwrap();
+            let received_message = match received {
+                Selected::Recv(1, message) => message,
+                _ => panic!("Expected the channel receive to fire"),
+            };
+
+            let timed_out = coroutine_handle.select(&[
+                WaitSpec::readable(&registration),
+                WaitSpec::timer(StdDuration::from_millis(100)),
+                WaitSpec::recv(&receiver_guard),
+            ]).unwrap();
+            match timed_out {
+                Selected::Timer(1) => received_message,
+                _ => panic!("Expected the timer to fire"),
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    sender.send(7).unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), 7);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_every_coroutine_selecting_on_a_join_is_woken() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let child = coroutine_handle.spawn(
+                |mut child_handle: IoHandle| {
+                    child_handle.sleep(StdDuration::from_millis(200)).unwrap();
+                },
+                STACK_SIZE,
+            ).unwrap();
+
+            let mut selectors = Vec::new();
+            for _ in 0..2 {
+                let join_waiter = child.join_waiter.clone();
+                selectors.push(coroutine_handle.spawn(
+                    move |mut selector_handle: IoHandle| {
+                        let selected = selector_handle.select::<u8>(&[
+                            WaitSpec::Join(join_waiter),
+                            WaitSpec::timer(StdDuration::from_millis(5 * 1000)),
+                        ]).unwrap();
+
+                        selected.index()
+                    },
+                    STACK_SIZE,
+                ).unwrap());
+            }
+
+            let mut selected_indexes = Vec::new();
+            for selector in selectors.iter_mut() {
+                selected_indexes.push(coroutine_handle.join(selector).unwrap().unwrap());
+            }
+
+            selected_indexes
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), vec![0, 0]);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_channel_send_does_not_wait_for_the_receiver() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (sender, receiver) = channel::new::<u8>();
+    let receiver_mutex = Mutex::new(receiver);
+
+    let producer = std::thread::spawn(move || {
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+    });
+    producer.join().unwrap();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let receiver_guard = receiver_mutex.lock().unwrap();
+            let first = coroutine_handle.recv(&receiver_guard).unwrap();
+            let second = coroutine_handle.recv(&receiver_guard).unwrap();
+
+            (first, second)
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), (1, 2));
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_threads_block_receiving_from_coroutines() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (sender, receiver) = channel::new::<u8>();
+
+    let consumer = std::thread::spawn(move || {
+        let first = receiver.recv().unwrap();
+        let closed = receiver.recv().is_err();
+
+        (first, closed)
+    });
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.sleep(StdDuration::from_millis(100)).unwrap();
+            sender.send(1).unwrap();
+            coroutine_handle.sleep(StdDuration::from_millis(100)).unwrap();
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    guard.join().unwrap().unwrap();
+    assert_eq!(consumer.join().unwrap(), (1, true));
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_bounded_channel_parks_senders_while_full() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let (sender, receiver) = channel::bounded::<u8>(1).unwrap();
+    let receiver_mutex = Mutex::new(receiver);
+
+    let mut producer_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.send(&sender, 1).unwrap();
+            let is_full = match sender.try_send(2) {
+                Err(CorosError::ChannelFull) => true,
+                _ => false,
+            };
+            coroutine_handle.send(&sender, 2).unwrap();
+            coroutine_handle.send(&sender, 3).unwrap();
+
+            is_full
+        },
+        STACK_SIZE,
+    ).unwrap();
+    let mut consumer_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.sleep(StdDuration::from_millis(200)).unwrap();
+            let receiver_guard = receiver_mutex.lock().unwrap();
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                received.push(coroutine_handle.recv(&receiver_guard).unwrap());
+            }
+
+            received
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(producer_guard.join().unwrap().unwrap());
+    assert_eq!(consumer_guard.join().unwrap().unwrap(), vec![1, 2, 3]);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_threads_block_sending_on_a_full_bounded_channel() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (sender, receiver) = channel::bounded::<u8>(1).unwrap();
+    let receiver_mutex = Mutex::new(receiver);
+
+    let producer = std::thread::spawn(move || {
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        sender.send(3).unwrap();
+    });
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let receiver_guard = receiver_mutex.lock().unwrap();
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                coroutine_handle.sleep(StdDuration::from_millis(50)).unwrap();
+                received.push(coroutine_handle.recv(&receiver_guard).unwrap());
+            }
+
+            received
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), vec![1, 2, 3]);
+    producer.join().unwrap();
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_bounded_channel_needs_capacity() {
+    match channel::bounded::<u8>(0) {
+        Err(CorosError::ZeroCapacityChannel) => {},
+        _ => panic!("Expected a zero capacity channel to be refused"),
+    }
+}
+
+#[test]
+fn test_cloned_receivers_share_a_channel() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let (sender, receiver) = channel::new::<u32>();
+
+    let mut guards = Vec::new();
+    for _ in 0..3 {
+        let receiver = receiver.clone();
+        guards.push(pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                let mut total = 0;
+                loop {
+                    match coroutine_handle.recv(&receiver).unwrap() {
+                        0 => return total,
+                        value => total += value,
+                    }
+                }
+            },
+            STACK_SIZE,
+        ).unwrap());
+    }
+
+    pool.start().unwrap();
+    for value in 1..31 {
+        sender.send(value).unwrap();
+    }
+    for _ in 0..3 {
+        sender.send(0).unwrap();
+    }
+    let total: u32 = guards
+        .iter_mut()
+        .map(|guard| guard.join().unwrap().unwrap())
+        .fold(0, |total, received| total + received);
+    assert_eq!(total, 465);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_dropping_every_sender_closes_the_channel() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let (sender, receiver) = channel::new::<u8>();
+    let cloned_sender = sender.clone();
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let received = coroutine_handle.recv(&receiver).unwrap();
+            let is_closed = match coroutine_handle.recv(&receiver) {
+                Err(CorosError::ChannelClosed) => true,
+                _ => false,
+            };
+
+            is_closed && received == 1
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    sender.send(1).unwrap();
+    drop(sender);
+    std::thread::sleep(StdDuration::from_millis(100));
+    drop(cloned_sender);
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_dropping_every_receiver_closes_the_channel() {
+    let (sender, receiver) = channel::new::<u8>();
+    match receiver.try_recv() {
+        Err(CorosError::ChannelEmpty) => {},
+        _ => panic!("Expected the channel to be empty"),
+    }
+    drop(receiver);
+
+    match sender.send(1) {
+        Err(CorosError::ChannelClosed) => {},
+        _ => panic!("Expected the channel to be closed"),
+    }
+}
+
+#[test]
+fn test_oneshot() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let (response_sender, response_receiver) = channel::oneshot::<String>();
+
+    let mut responder_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.sleep(StdDuration::from_millis(100)).unwrap();
+            response_sender.send("pong".to_string()).unwrap();
+        },
+        STACK_SIZE,
+    ).unwrap();
+    let mut requester_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.recv_oneshot(response_receiver).unwrap()
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    responder_guard.join().unwrap().unwrap();
+    assert_eq!(requester_guard.join().unwrap().unwrap(), "pong");
+    pool.stop().unwrap();
+
+    let (dropped_sender, dropped_receiver) = channel::oneshot::<String>();
+    drop(dropped_receiver);
+    match dropped_sender.send("ping".to_string()) {
+        Err(CorosError::ChannelClosed) => {},
+        _ => panic!("Expected the oneshot to be closed"),
+    }
+}
+
+#[test]
+fn test_broadcast_reaches_every_receiver() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let (config_sender, config_receiver) = channel::broadcast::<u32>(4);
+
+    let mut guards = Vec::new();
+    for _ in 0..3 {
+        let mut config_receiver = config_receiver.clone();
+        let guard = pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                let mut configs = Vec::new();
+                loop {
+                    match coroutine_handle.recv_broadcast(&mut config_receiver) {
+                        Ok(config) => configs.push(config),
+                        Err(CorosError::ChannelClosed) => return configs,
+                        Err(err) => panic!("Unexpected error receiving broadcast: {:?}", err),
+                    }
+                }
+            },
+            STACK_SIZE,
+        ).unwrap();
+        guards.push(guard);
+    }
+    drop(config_receiver);
+
+    pool.start().unwrap();
+    std::thread::sleep(StdDuration::from_millis(100));
+    for config in 0..3 {
+        config_sender.send(config).unwrap();
+    }
+    drop(config_sender);
+
+    for mut guard in guards {
+        assert_eq!(guard.join().unwrap().unwrap(), vec![0, 1, 2]);
+    }
+    pool.stop().unwrap();
+
+    let (lagging_sender, mut lagging_receiver) = channel::broadcast::<u32>(2);
+    for message in 0..5 {
+        lagging_sender.send(message).unwrap();
+    }
+    match lagging_receiver.try_recv() {
+        Err(CorosError::Lagged(3)) => {},
+        result => panic!("Expected the receiver to have lagged, got {:?}", result),
+    }
+    assert_eq!(lagging_receiver.try_recv().unwrap(), 3);
+    assert_eq!(lagging_receiver.try_recv().unwrap(), 4);
+}
+
+#[test]
+fn test_watch_wakes_receivers_when_the_value_changes() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let (shutdown_sender, shutdown_receiver) = channel::watch(false);
+
+    let mut guards = Vec::new();
+    for _ in 0..3 {
+        let mut shutdown_receiver = shutdown_receiver.clone();
+        let guard = pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                while !coroutine_handle.changed(&mut shutdown_receiver).unwrap() {}
+                shutdown_receiver.get()
+            },
+            STACK_SIZE,
+        ).unwrap();
+        guards.push(guard);
+    }
+
+    pool.start().unwrap();
+    std::thread::sleep(StdDuration::from_millis(100));
+    shutdown_sender.send(true);
+
+    for mut guard in guards {
+        assert!(guard.join().unwrap().unwrap());
+    }
+    pool.stop().unwrap();
+
+    drop(shutdown_sender);
+    let mut closed_receiver = shutdown_receiver.clone();
+    match closed_receiver.try_changed() {
+        Err(CorosError::ChannelClosed) => {},
+        result => panic!("Expected the watch to be closed, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_mutex_can_be_held_while_suspended() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let counter = Arc::new(sync::Mutex::new(0));
+
+    let mut guards = Vec::new();
+    for _ in 0..4 {
+        let counter = counter.clone();
+        let guard = pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                let mut count = counter.lock(&mut coroutine_handle).unwrap();
+                let previous_count = *count;
+                coroutine_handle.sleep(StdDuration::from_millis(20)).unwrap();
+                *count = previous_count + 1;
+            },
+            STACK_SIZE,
+        ).unwrap();
+        guards.push(guard);
+    }
+
+    pool.start().unwrap();
+    for mut guard in guards {
+        guard.join().unwrap().unwrap();
+    }
+    pool.stop().unwrap();
+
+    assert_eq!(*counter.try_lock().unwrap(), 4);
+}
+
+#[test]
+fn test_rwlock_writers_wait_for_readers() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let config = Arc::new(sync::RwLock::new("old".to_string()));
+
+    let reader_config = config.clone();
+    let mut reader_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let config = reader_config.read(&mut coroutine_handle).unwrap();
+            coroutine_handle.sleep(StdDuration::from_millis(100)).unwrap();
+            config.clone()
+        },
+        STACK_SIZE,
+    ).unwrap();
+    let writer_config = config.clone();
+    let mut writer_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            coroutine_handle.sleep(StdDuration::from_millis(20)).unwrap();
+            let mut config = writer_config.write(&mut coroutine_handle).unwrap();
+            *config = "new".to_string();
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(reader_guard.join().unwrap().unwrap(), "old");
+    writer_guard.join().unwrap().unwrap();
+    pool.stop().unwrap();
+
+    assert_eq!(*config.try_read().unwrap(), "new");
+}
+
+#[test]
+fn test_semaphore_limits_concurrency() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let semaphore = Arc::new(sync::Semaphore::new(2));
+    let running = Arc::new(Mutex::new((0, 0)));
+
+    let mut guards = Vec::new();
+    for _ in 0..6 {
+        let semaphore = semaphore.clone();
+        let running = running.clone();
+        let guard = pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                let _permit = semaphore.acquire(&mut coroutine_handle).unwrap();
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = std::cmp::max(running.0, running.1);
+                }
+                coroutine_handle.sleep(StdDuration::from_millis(20)).unwrap();
+                running.lock().unwrap().0 -= 1;
+            },
+            STACK_SIZE,
+        ).unwrap();
+        guards.push(guard);
+    }
+
+    pool.start().unwrap();
+    for mut guard in guards {
+        guard.join().unwrap().unwrap();
+    }
+    pool.stop().unwrap();
+
+    assert_eq!(running.lock().unwrap().1, 2);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test]
+fn test_wait_group_barrier_and_notify() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let barrier = Arc::new(sync::Barrier::new(3));
+    let notify = Arc::new(sync::Notify::new());
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let wait_group = sync::WaitGroup::new();
+            let mut leaders = Vec::new();
+            for _ in 0..3 {
+                wait_group.add(1);
+                let wait_group = wait_group.clone();
+                let barrier = barrier.clone();
+                let notify = notify.clone();
+                let child = coroutine_handle.spawn(
+                    move |mut coroutine_handle: IoHandle| {
+                        let is_leader = barrier.wait(&mut coroutine_handle).unwrap();
+                        notify.notified(&mut coroutine_handle).unwrap();
+                        wait_group.done();
+                        is_leader
+                    },
+                    STACK_SIZE,
+                ).unwrap();
+                leaders.push(child);
+            }
+
+            coroutine_handle.sleep(StdDuration::from_millis(50)).unwrap();
+            assert_eq!(wait_group.count(), 3);
+            notify.notify_all();
+            wait_group.wait(&mut coroutine_handle).unwrap();
+
+            let mut leader_count = 0;
+            for mut child in leaders {
+                if coroutine_handle.join(&mut child).unwrap().unwrap() {
+                    leader_count += 1;
+                }
+            }
+            leader_count
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert_eq!(guard.join().unwrap().unwrap(), 1);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_barrier_does_not_count_waiters_that_timed_out() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let barrier = Arc::new(sync::Barrier::new(2));
+
+    let mut guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let first_wait = coroutine_handle.with_deadline(
+                StdDuration::from_millis(50),
+                |coroutine_handle| barrier.wait(coroutine_handle),
+            );
+            let second_wait = coroutine_handle.with_deadline(
+                StdDuration::from_millis(50),
+                |coroutine_handle| barrier.wait(coroutine_handle),
+            );
+
+            match (first_wait, second_wait) {
+                (Err(CorosError::TimedOut), Err(CorosError::TimedOut)) => true,
+                _ => false,
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    assert!(guard.join().unwrap().unwrap());
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_notify_one_wakes_a_waiter_each_time() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let notify = Arc::new(sync::Notify::new());
+
+    let mut guards = Vec::new();
+    for _ in 0..2 {
+        let notify = notify.clone();
+        let guard = pool.spawn(
+            move |mut coroutine_handle: IoHandle| {
+                coroutine_handle.with_deadline(
+                    StdDuration::from_millis(1000),
+                    |coroutine_handle| notify.notified(coroutine_handle),
+                ).is_ok()
+            },
+            STACK_SIZE,
+        ).unwrap();
+        guards.push(guard);
+    }
+
+    pool.start().unwrap();
+    std::thread::sleep(StdDuration::from_millis(100));
+    notify.notify_one();
+    notify.notify_one();
+    for mut guard in guards {
+        assert!(guard.join().unwrap().unwrap());
+    }
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_condvar_producer_consumer() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    let queue = Arc::new((sync::Mutex::new(Vec::new()), sync::Condvar::new()));
+
+    let consumer_queue = queue.clone();
+    let mut consumer_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let (ref items, ref items_available) = *consumer_queue;
+            let mut received = Vec::new();
+            while received.len() < 3 {
+                let mut items = items.lock(&mut coroutine_handle).unwrap();
+                while items.is_empty() {
+                    items = items_available.wait(&mut coroutine_handle, items).unwrap();
+                }
+                received.extend(items.drain(..));
+            }
+            received
+        },
+        STACK_SIZE,
+    ).unwrap();
+    let producer_queue = queue.clone();
+    let mut producer_guard = pool.spawn(
+        move |mut coroutine_handle: IoHandle| {
+            let (ref items, ref items_available) = *producer_queue;
+            for item in 0..3 {
+                coroutine_handle.sleep(StdDuration::from_millis(20)).unwrap();
+                items.lock(&mut coroutine_handle).unwrap().push(item);
+                items_available.notify_one();
+            }
+        },
+        STACK_SIZE,
+    ).unwrap();
+
+    pool.start().unwrap();
+    producer_guard.join().unwrap().unwrap();
+    assert_eq!(consumer_guard.join().unwrap().unwrap(), vec![0, 1, 2]);
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_coroutine_panic_keeps_its_payload() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 1).unwrap();
+    let mut guard = pool.spawn(
+        |_| -> u32 { panic!("request {} failed", 7) },
+        STACK_SIZE,
+    ).unwrap();
+    let resumed_guard = pool.spawn(
+        |_| -> u32 { panic!("resumed") },
+        STACK_SIZE,
+    ).unwrap();
+    pool.start().unwrap();
+
+    let coroutine_id = guard.coroutine_id();
+    match guard.join().unwrap() {
+        Err(CorosError::CoroutinePanic(details)) => {
+            assert_eq!(details.coroutine_id, coroutine_id);
+            assert_eq!(details.message(), Some("request 7 failed"));
+        },
+        result => panic!("Expected the coroutine to panic, got {:?}", result),
+    }
+
+    let mut resumed_guard = resumed_guard;
+    let joining_thread = std::thread::spawn(move || {
+        resumed_guard.join_resuming_panic()
+    });
+    let payload = joining_thread.join().err().unwrap();
+    assert_eq!(*payload.downcast_ref::<&'static str>().unwrap(), "resumed");
+    pool.stop().unwrap();
+}
+
+#[test]
+fn test_finished_coroutines_stacks_are_reused() {
+    let mut pool = PoolBuilder::new()
+        .thread_count(1)
+        .max_cached_stacks_per_size_class(4)
+        .build()
+        .unwrap();
+    pool.start().unwrap();
+
+    let mut stack_addresses = Vec::new();
+    for _ in 0..8 {
+        let mut guard = pool.spawn(|_| {
+            let local = 0u8;
+            &local as *const u8 as usize
+        }, STACK_SIZE).unwrap();
+        stack_addresses.push(guard.join().unwrap().unwrap());
+    }
+    assert!(stack_addresses.iter().all(|address| *address == stack_addresses[0]));
+    pool.stop().unwrap();
+
+    let mut uncached_pool = PoolBuilder::new()
+        .thread_count(1)
+        .max_cached_stacks_per_size_class(0)
+        .build()
+        .unwrap();
+    uncached_pool.start().unwrap();
+    for index in 0..8 {
+        let mut guard = uncached_pool.spawn(move |_| { index }, STACK_SIZE).unwrap();
+        assert_eq!(index, guard.join().unwrap().unwrap());
+    }
+    uncached_pool.stop().unwrap();
+}
+
+fn use_stack(depth: usize) -> usize {
+    let mut frame = [depth as u8; 1024];
+    if depth == 0 {
+        return frame.len()
+    }
+    frame[depth] = use_stack(depth - 1) as u8;
+
+    frame.iter().fold(0, |total, byte| total + *byte as usize)
+}
+
+#[test]
+fn test_stack_usage_is_measured_when_enabled() {
+    let mut pool = PoolBuilder::new()
+        .thread_count(1)
+        .measure_stack_usage(true)
+        .build()
+        .unwrap();
+    let mut guard = pool.spawn(|_| { use_stack(64) }, STACK_SIZE).unwrap();
+    pool.start().unwrap();
+
+    guard.join().unwrap().unwrap();
+    let stack_high_water_mark = guard.stack_high_water_mark().unwrap();
+    assert!(stack_high_water_mark >= 64 * 1024);
+    assert!(stack_high_water_mark < STACK_SIZE);
+
+    let histogram = pool.stack_usage_histogram().unwrap();
+    assert_eq!(1, histogram.count());
+    assert_eq!(stack_high_water_mark, histogram.max());
+    assert_eq!(vec![(stack_high_water_mark.next_power_of_two(), 1)], histogram.buckets());
+    pool.stop().unwrap();
+
+    let mut unmeasured_pool = Pool::new("pool_name".to_string(), 1).unwrap();
+    let mut unmeasured_guard = unmeasured_pool.spawn(|_| { use_stack(1) }, STACK_SIZE).unwrap();
+    unmeasured_pool.start().unwrap();
+    unmeasured_guard.join().unwrap().unwrap();
+    assert_eq!(None, unmeasured_guard.stack_high_water_mark());
+    assert!(unmeasured_pool.stack_usage_histogram().is_none());
+    unmeasured_pool.stop().unwrap();
+}
+
+/// Set when the test binary is run again as the child that overflows
+const OVERFLOW_CHILD_ENV_VAR: &'static str = "COROS_TEST_STACK_OVERFLOW_CHILD";
+
+#[test]
+fn test_stack_overflow_is_reported_and_aborts() {
+    if env::var(OVERFLOW_CHILD_ENV_VAR).is_ok() {
+        let mut pool = Pool::new("overflowing_pool".to_string(), 1).unwrap();
+        let mut guard = pool.spawn(|_| { use_stack(1000) }, 64 * 1024).unwrap();
+        pool.start().unwrap();
+        guard.join().unwrap().unwrap();
+        return
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .arg("test_stack_overflow_is_reported_and_aborts")
+        .env(OVERFLOW_CHILD_ENV_VAR, "1")
+        .output()
+        .unwrap();
+
+    assert_eq!(Some(libc::SIGABRT), output.status.signal());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("in pool overflowing_pool overflowed its stack, aborting"));
+}
+
+#[test]
+fn test_generator_yields_values_then_returns() {
+    let mut generator = Generator::new(|generator_handle: &mut GeneratorHandle<u32, &'static str>| {
+        for value in 0..4 {
+            generator_handle.yield_value(value * value);
+        }
+        "finished"
+    }, STACK_SIZE).unwrap();
+
+    assert_eq!(None, generator.take_result());
+    assert_eq!(vec![0, 1, 4, 9], generator.by_ref().collect::<Vec<u32>>());
+    assert!(generator.is_finished());
+    assert_eq!(None, generator.next());
+    assert_eq!(Some("finished"), generator.take_result());
+
+    let was_dropped = Arc::new(Mutex::new(false));
+    let generator_was_dropped = was_dropped.clone();
+    let mut unfinished_generator = Generator::new(move |generator_handle: &mut GeneratorHandle<u32, ()>| {
+        let _drop_flag = DropFlag(generator_was_dropped);
+        loop {
+            generator_handle.yield_value(1);
+        }
+    }, STACK_SIZE).unwrap();
+    assert_eq!(Some(1), unfinished_generator.next());
+    drop(unfinished_generator);
+    assert!(*was_dropped.lock().unwrap());
+}
+
+#[test]
+fn test_finished_generator_drops_what_its_closure_captured() {
+    let was_dropped = Arc::new(Mutex::new(false));
+    let drop_flag = DropFlag(was_dropped.clone());
+    let mut generator = Generator::new(move |generator_handle: &mut GeneratorHandle<u32, ()>| {
+        let _drop_flag = drop_flag;
+        generator_handle.yield_value(1);
+    }, STACK_SIZE).unwrap();
+
+    assert_eq!(vec![1], generator.by_ref().collect::<Vec<u32>>());
+    assert!(generator.is_finished());
+    assert!(*was_dropped.lock().unwrap());
+}
+
+#[test]
+fn test_scoped_coroutines_can_borrow_from_the_caller() {
+    let pool_name = "pool_name".to_string();
+    let mut pool = Pool::new(pool_name, 2).unwrap();
+    pool.start().unwrap();
+
+    let numbers: Vec<usize> = (1..101).collect();
+    let total = Mutex::new(0);
+    let chunk_count = pool.scope(|scope| {
+        let mut chunk_count = 0;
+        for chunk in numbers.chunks(10) {
+            let total = &total;
+            scope.spawn(move |_| {
+                *total.lock().unwrap() += chunk.iter().fold(0, |sum, number| sum + number);
+            }, STACK_SIZE).unwrap();
+            chunk_count += 1;
+        }
+
+        chunk_count
+    });
+
+    assert_eq!(10, chunk_count);
+    assert_eq!(5050, *total.lock().unwrap());
+    pool.stop().unwrap();
+}